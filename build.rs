@@ -6,7 +6,8 @@ fn main() -> io::Result<()> {
         .compile(&[
             "proto/replica.proto",
             "proto/joinreq.proto",
-            "proto/syncreq.proto"
+            "proto/syncreq.proto",
+            "proto/client.proto"
         ], &["proto"])?;
 
     Ok(())
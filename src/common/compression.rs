@@ -0,0 +1,90 @@
+use std::env;
+use std::io::{self, Read, Write};
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tonic::codec::CompressionEncoding;
+
+lazy_static! {
+    pub static ref REPLICATION_COMPRESSION: Option<Codec> = Codec::parse(
+        &env::var("REPLICATION_COMPRESSION").unwrap_or(String::from("gzip"))
+    );
+    pub static ref PAYLOAD_COMPRESSION: Option<Codec> = Codec::parse(
+        &env::var("PAYLOAD_COMPRESSION").unwrap_or_default()
+    );
+    pub static ref PAYLOAD_COMPRESSION_MIN_BYTES: usize = env::var("PAYLOAD_COMPRESSION_MIN_BYTES")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(256);
+}
+
+
+#[derive(Debug, Serialize, PartialEq, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+
+    const ZSTD_LEVEL: i32 = 3;
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "gzip" => Some(Self::Gzip),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(codec: Option<Self>) -> String {
+        match codec {
+            Some(Self::Gzip) => String::from("gzip"),
+            Some(Self::Zstd) => String::from("zstd"),
+            None => String::new(),
+        }
+    }
+
+    /// Codecs this build is able to (de)compress, advertised by a node on join
+    pub fn supported() -> Vec<String> {
+        vec![Self::name(Some(Self::Gzip)), Self::name(Some(Self::Zstd))]
+    }
+
+    /// Picks the cluster replication codec if the node has offered it, otherwise the traffic goes uncompressed
+    pub fn negotiate(offered: &[String]) -> Option<Self> {
+        REPLICATION_COMPRESSION.filter(|codec| offered.iter().any(|name| Self::parse(name) == Some(*codec)))
+    }
+
+    pub fn encoding(&self) -> CompressionEncoding {
+        match self {
+            Self::Gzip => CompressionEncoding::Gzip,
+            Self::Zstd => CompressionEncoding::Zstd,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Self::Zstd => zstd::encode_all(data, Self::ZSTD_LEVEL),
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut decoded = Vec::new();
+                GzDecoder::new(data).read_to_end(&mut decoded)?;
+                Ok(decoded)
+            }
+            Self::Zstd => zstd::decode_all(data),
+        }
+    }
+}
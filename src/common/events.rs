@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+use std::env;
+
+use actix_web::{web::Bytes, HttpRequest, HttpResponse};
+use futures::{stream, Stream, StreamExt};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use tokio::sync::watch;
+
+use crate::common::message::{timestamp_ms, Message, MessageLog};
+use crate::common::utils::ErrorBody;
+
+lazy_static! {
+    static ref STREAM_READ_SIZE: usize = env::var("STREAM_READ_SIZE")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(100);
+}
+
+
+/// `?from_order=` to start the stream from, the `Last-Event-ID` header takes precedence
+#[derive(Debug, Deserialize)]
+pub struct StreamQuery {
+    pub from_order: Option<u32>,
+}
+
+pub(crate) struct Tail {
+    log: MessageLog,
+    appended: watch::Receiver<()>,
+    next: u32,
+    contiguous: bool,
+    buffer: VecDeque<Message>,
+}
+
+impl Tail {
+
+    pub(crate) fn new(log: &MessageLog, next: u32, contiguous: bool) -> Self {
+        Self {
+            log: MessageLog::from(log),
+            appended: log.subscribe(),
+            next,
+            contiguous,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Waits for the messages following the last sent one, a contiguous tail stops at the first gap in the ordering.
+    /// Cancel safe, nothing is lost if the future is dropped before completion
+    pub(crate) async fn next(&mut self) -> Option<Message> {
+        loop {
+            if let Some(message) = self.buffer.pop_front() {
+                return Some(message)
+            }
+
+            // marked as seen before the read, so an append right after it is not missed
+            self.appended.borrow_and_update();
+            let mut expected = self.next;
+            let now = timestamp_ms();
+            for message in self.log.slots(self.next, *STREAM_READ_SIZE).await {
+                if self.contiguous && message.order != expected {
+                    break
+                }
+                expected = message.order + 1;
+                // an expired message fills its slot without being sent
+                if !message.expired(now) {
+                    self.buffer.push_back(message);
+                }
+            }
+
+            match expected > self.next {
+                true => self.next = expected,
+                false => self.appended.changed().await.ok()?,
+            }
+        }
+    }
+}
+
+/// The ordering a tail starts from: the given one, but not before the first one, since a contiguous tail
+/// would wait for the slot `0` forever, or the one right after the current head
+pub async fn start(log: &MessageLog, from_order: Option<u32>) -> u32 {
+    match from_order {
+        Some(from) => from.max(1),
+        None => log.head().await.saturating_add(1),
+    }
+}
+
+/// The messages appended to the log starting from the ordering `next`, endless until the subscriber is dropped
+pub fn tail(log: &MessageLog, next: u32, contiguous: bool) -> impl Stream<Item = Message> {
+    stream::unfold(Tail::new(log, next, contiguous), |mut tail| async move {
+        let message = tail.next().await?;
+        Some((message, tail))
+    })
+}
+
+fn event(message: &Message) -> Bytes {
+    let data = serde_json::to_string(message).unwrap_or_default();
+    Bytes::from(format!("id: {}\nevent: message\ndata: {data}\n\n", message.order))
+}
+
+/// Streams the messages appended to the log as Server-Sent Events with their ordering as the event ID.
+/// Starts right after `Last-Event-ID`, from `from_order` or from the current head
+pub async fn stream(log: &MessageLog, query: &StreamQuery, req: &HttpRequest, contiguous: bool) -> HttpResponse {
+    let last_event_id = req.headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.trim().parse::<u32>().ok());
+    let next = match last_event_id {
+        Some(last) => match last.checked_add(1) {
+            Some(next) => next,
+            None => return HttpResponse::BadRequest().json(ErrorBody::new(format!("No ordering after ({last})"))),
+        },
+        None => start(log, query.from_order).await,
+    };
+    log::info!("Streaming the messages from ordering ({next}), contiguous - {contiguous}");
+
+    let events = tail(log, next, contiguous).map(|message| Ok::<_, actix_web::Error>(event(&message)));
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
@@ -0,0 +1,141 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::events::Tail;
+use crate::common::message::{Message, MessageLog};
+use crate::common::topics::TopicLogs;
+
+/// The internal topic the committed offsets are appended to, so they are replicated like messages
+pub const OFFSETS_TOPIC: &str = "__consumer_offsets";
+
+
+/// The last ordering of a topic partition consumed by a group, the content of a message of the offsets topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OffsetCommit {
+    pub group: String,
+    pub topic: String,
+    pub partition: u32,
+    pub order: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Committed {
+    order: u32,
+    // the ordering of the commit in the offsets topic
+    at: u32,
+}
+
+/// `{group}` path segment
+#[derive(Debug, Deserialize)]
+pub struct GroupPath {
+    pub group: String,
+}
+
+/// `{group}/offsets/{topic}/{partition}` path segments
+#[derive(Debug, Deserialize)]
+pub struct OffsetPath {
+    pub group: String,
+    pub topic: String,
+    pub partition: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PartitionLag {
+    pub topic: String,
+    pub partition: u32,
+    pub order: u32,  // the committed ordering
+    pub head: u32,
+    pub lag: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupLag {
+    pub group: String,
+    pub lag: u32,  // across every partition of the group
+    pub offsets: Vec<PartitionLag>,
+}
+
+/// The committed offsets of a group by topic and partition
+type Offsets = BTreeMap<(String, u32), Committed>;
+
+/// The committed offsets of every consumer group, materialized from the offsets topic
+#[derive(Debug, Clone, Default)]
+pub struct ConsumerGroups(Arc<RwLock<HashMap<String, Offsets>>>);
+
+impl ConsumerGroups {
+
+    /// Applies the commit unless a later one of the same partition is applied already,
+    /// `at` is the ordering of the commit in the offsets topic
+    pub fn apply(&self, commit: OffsetCommit, at: u32) {
+        let mut groups = self.0.write().unwrap();
+        let offsets = groups.entry(commit.group).or_default();
+        let committed = offsets.entry((commit.topic, commit.partition)).or_insert(Committed { order: 0, at: 0 });
+        if committed.at < at {
+            *committed = Committed { order: commit.order, at };
+        }
+    }
+
+    fn apply_message(&self, message: &Message) {
+        match serde_json::from_slice::<OffsetCommit>(&message.content) {
+            Ok(commit) => {
+                log::debug!("Offset of `{}` at `{}/{}` committed - ({})", commit.group, commit.topic, commit.partition, commit.order);
+                self.apply(commit, message.order)
+            }
+            Err(e) => log::warn!("Invalid offset commit ({}) skipped - {e}", message.order),
+        }
+    }
+
+    /// Applies the commits of the offsets topic log in the ordering sequence as they are replicated
+    pub async fn follow(self, log: MessageLog) {
+        let mut tail = Tail::new(&log, 1, true);
+        while let Some(message) = tail.next().await {
+            self.apply_message(&message);
+        }
+    }
+
+    pub fn fetch(&self, group: &str, topic: &str, partition: u32) -> Option<u32> {
+        let groups = self.0.read().unwrap();
+        groups.get(group)?.get(&(topic.to_string(), partition)).map(|committed| committed.order)
+    }
+
+    /// The committed offset of the group with the distance to the head of the partition
+    pub async fn offset(&self, group: &str, topic: &str, partition: u32, logs: &TopicLogs) -> Option<PartitionLag> {
+        let order = self.fetch(group, topic, partition)?;
+        let head = logs.get(topic, partition)?.head().await;
+
+        Some(PartitionLag { topic: topic.to_string(), partition, order, head, lag: head.saturating_sub(order) })
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.0.read().unwrap().keys().cloned().collect::<Vec<_>>();
+        names.sort();
+
+        names
+    }
+
+    /// The committed offsets of the group with the distance to the head of every partition,
+    /// the partitions of the deleted topics are skipped
+    pub async fn lag(&self, group: &str, logs: &TopicLogs) -> Option<GroupLag> {
+        let offsets = self.0
+            .read()
+            .unwrap()
+            .get(group)?
+            .iter()
+            .map(|((topic, partition), committed)| (topic.clone(), *partition, committed.order))
+            .collect::<Vec<_>>();
+
+        let mut report = GroupLag { group: group.to_string(), lag: 0, offsets: vec![] };
+        for (topic, partition, order) in offsets {
+            if let Some(log) = logs.get(&topic, partition) {
+                let head = log.head().await;
+                let lag = head.saturating_sub(order);
+                report.lag += lag;
+                report.offsets.push(PartitionLag { topic, partition, order, head, lag });
+            }
+        }
+
+        Some(report)
+    }
+}
@@ -1,37 +1,449 @@
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use log;
 use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
+
+use crate::common::compression::{Codec, PAYLOAD_COMPRESSION, PAYLOAD_COMPRESSION_MIN_BYTES};
+use crate::common::utils::{fnv1a_extend, FNV1A_OFFSET_BASIS};
+
+pub const DEFAULT_CONTENT_TYPE: &str = "text/plain";
+pub const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Message {
-    pub content: String,
+    pub id: String,
+    pub order: u32,
+    #[serde(flatten, with = "payload")]
+    pub content: Vec<u8>,
+    pub headers: HashMap<String, String>,
+    pub content_type: String,
+    pub timestamp: u64,  // the append time on the master, ms since the UNIX epoch
+    pub producer_id: Option<String>,
+    pub key: Option<String>,  // the partition routing key
+    pub redacts: Option<String>,  // the ID of the message a tombstone redacts
+    pub redacted: bool,  // the content has been dropped by a tombstone, the ordering slot is kept
+    pub ttl_ms: Option<u64>,  // the message is hidden from the reads and reclaimed after it
+}
+
+impl Message {
+    pub fn expired(&self, now: u64) -> bool {
+        expired(self.timestamp, self.ttl_ms, now)
+    }
+}
+
+/// Whether a message appended at `timestamp` has outlived its TTL by `now`
+pub fn expired(timestamp: u64, ttl_ms: Option<u64>, now: u64) -> bool {
+    ttl_ms.is_some_and(|ttl| timestamp.saturating_add(ttl) <= now)
+}
+
+/// The content type of a payload that comes without one
+pub fn default_content_type(content: &[u8]) -> &'static str {
+    match std::str::from_utf8(content) {
+        Ok(_) => DEFAULT_CONTENT_TYPE,
+        Err(_) => BINARY_CONTENT_TYPE,
+    }
+}
+
+/// Milliseconds since the UNIX epoch
+pub fn timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// The payload in JSON: a UTF-8 `content` as is or any other one as a base64 `content_base64`
+pub mod payload {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Fields {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content_base64: Option<String>,
+    }
+
+    /// Takes either the UTF-8 `text` or the base64 `encoded` payload, not both
+    pub fn decode(text: Option<String>, encoded: Option<String>) -> Result<Vec<u8>, String> {
+        match (text, encoded) {
+            (Some(text), None) => Ok(text.into_bytes()),
+            (None, Some(encoded)) => STANDARD.decode(encoded).map_err(|e| format!("Invalid base64 payload - {e}")),
+            (Some(_), Some(_)) => Err(String::from("Either a text or a base64 payload is expected, not both")),
+            (None, None) => Err(String::from("The payload is missing")),
+        }
+    }
+
+    pub fn serialize<S: Serializer>(content: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = match std::str::from_utf8(content) {
+            Ok(text) => Fields { content: Some(text.to_string()), content_base64: None },
+            Err(_) => Fields { content: None, content_base64: Some(STANDARD.encode(content)) },
+        };
+
+        fields.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let fields = Fields::deserialize(deserializer)?;
+
+        decode(fields.content, fields.content_base64).map_err(de::Error::custom)
+    }
+}
+
+/// Digest of a sequence of `(ordering, content)` log entries.
+/// Sensitive to the entry placement, so a lost, repeated or misplaced entry changes it.
+/// Every field is hashed as little-endian bytes with FNV-1a, so the nodes agree whatever toolchain or platform
+/// they are built with, the content length is hashed too, so the entry boundaries cannot shift
+pub fn range_digest<'a, I>(entries: I) -> u64
+where
+    I: IntoIterator<Item = (u32, &'a [u8])>,
+{
+    entries.into_iter().fold(FNV1A_OFFSET_BASIS, |hash, (order, content)| {
+        let hash = fnv1a_extend(hash, &order.to_le_bytes());
+        let hash = fnv1a_extend(hash, &(content.len() as u64).to_le_bytes());
+        fnv1a_extend(hash, content)
+    })
+}
+
+/// Compresses the payload with the codec, `None` if it is too small or does not shrink
+pub fn compress_payload(content: &[u8], codec: Option<Codec>, order: u32) -> Option<(Codec, Vec<u8>)> {
+    codec
+        .filter(|_| content.len() >= *PAYLOAD_COMPRESSION_MIN_BYTES)
+        .and_then(|codec| match codec.compress(content) {
+            Ok(payload) if payload.len() < content.len() => Some((codec, payload)),
+            Ok(_) => None,
+            Err(e) => {
+                log::error!("Failed to compress the message ({order}) - {e:?}");
+                None
+            }
+        })
+}
+
+#[derive(Debug, Serialize)]
+pub struct StorageMetrics {
+    pub messages: usize,
+    pub compression: Option<Codec>,
+    pub compressed_messages: usize,
+    pub raw_bytes: usize,
+    pub stored_bytes: usize,
+    pub ratio: f64,
+}
+
+impl StorageMetrics {
+    /// Sums up the raw and the stored sizes of the entries, the stored size is `None` for an uncompressed entry
+    pub fn new(compression: Option<Codec>, entries: impl Iterator<Item = (usize, Option<usize>)>) -> Self {
+        let (mut messages, mut compressed_messages, mut raw_bytes, mut stored_bytes) = (0, 0, 0, 0);
+        for (raw_len, compressed_len) in entries {
+            messages += 1;
+            compressed_messages += compressed_len.is_some() as usize;
+            raw_bytes += raw_len;
+            stored_bytes += compressed_len.unwrap_or(raw_len);
+        }
+
+        Self {
+            messages,
+            compression,
+            compressed_messages,
+            raw_bytes,
+            stored_bytes,
+            ratio: if stored_bytes == 0 { 1.0 } else { raw_bytes as f64 / stored_bytes as f64 },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    message: Message,
+    raw_len: usize,
+    // when compressed, the message content is moved into the payload
+    compressed: Option<(Codec, Vec<u8>)>,
+    // the payload of an expired message has been dropped by the sweeper
+    reclaimed: bool,
+}
+
+impl Entry {
+    fn store(mut message: Message, codec: Option<Codec>) -> Self {
+        let raw_len = message.content.len();
+        let compressed = compress_payload(&message.content, codec, message.order);
+        if compressed.is_some() {
+            message.content = vec![];
+        }
+
+        Self { message, raw_len, compressed, reclaimed: false }
+    }
+
+    /// A payload that cannot be decompressed is an error, it is never served as an empty content
+    fn load(&self) -> io::Result<Message> {
+        let mut message = self.message.clone();
+        if let Some((codec, payload)) = &self.compressed {
+            message.content = codec.decompress(payload).map_err(|e| {
+                log::error!("Failed to decompress the message ({}) - {e:?}", message.order);
+                e
+            })?;
+        }
+
+        Ok(message)
+    }
+
+    /// Drops the payload of an expired message, the entry itself is kept, so the ordering slot stays taken
+    /// and the contiguous readers do not stop at it
+    fn reclaim(&mut self) {
+        self.message.content = vec![];
+        self.message.headers.clear();
+        self.raw_len = 0;
+        self.compressed = None;
+        self.reclaimed = true;
+    }
+
+    /// Drops the content, the rest of the message stays in place
+    fn redact(&mut self) {
+        self.message.content = vec![];
+        self.message.redacted = true;
+        self.raw_len = 0;
+        self.compressed = None;
+    }
+}
+
+/// The log entries in the order they were appended, indexed by the message ID and the ordering
+#[derive(Debug, Default)]
+struct Entries {
+    log: Vec<Entry>,
+    by_id: HashMap<String, usize>,
+    // the latest entry of an ordering
+    by_order: BTreeMap<u32, usize>,
+    // the IDs of the messages redacted by the tombstones, a message that arrives after its tombstone is redacted right away
+    redacted: HashSet<String>,
+}
+
+impl Entries {
+    fn push(&mut self, mut entry: Entry) {
+        if let Some(target) = entry.message.redacts.clone() {
+            self.redact(target);
+        }
+        if self.redacted.contains(&entry.message.id) {
+            entry.redact();
+        }
+        self.by_id.insert(entry.message.id.clone(), self.log.len());
+        self.by_order.insert(entry.message.order, self.log.len());
+        self.log.push(entry);
+    }
+
+    fn redact(&mut self, id: String) {
+        if let Some(i) = self.by_id.get(&id) {
+            self.log[*i].redact();
+            log::info!("The message `{id}` redacted");
+        }
+        self.redacted.insert(id);
+    }
+
+    /// Has to be called after the entries are moved within the log
+    fn reindex(&mut self) {
+        self.by_id.clear();
+        self.by_order.clear();
+        for (i, entry) in self.log.iter().enumerate() {
+            self.by_id.insert(entry.message.id.clone(), i);
+            self.by_order.insert(entry.message.order, i);
+            if let Some(target) = &entry.message.redacts {
+                self.redacted.insert(target.clone());
+            }
+        }
+        for entry in self.log.iter_mut().filter(|entry| self.redacted.contains(&entry.message.id)) {
+            entry.redact();
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct MessageLog {
-    messages: Arc<Mutex<Vec<Message>>>,
+    messages: Arc<Mutex<Entries>>,
+    compression: Arc<RwLock<Option<Codec>>>,
+    // bumped on every change, so the subscribers know when to read again
+    appended: Arc<watch::Sender<()>>,
+}
+
+impl Default for MessageLog {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MessageLog {
     pub fn new() -> Self {
-        let messages = Arc::new(Mutex::new(vec![]));
+        let messages = Arc::new(Mutex::new(Entries::default()));
+        let compression = Arc::new(RwLock::new(*PAYLOAD_COMPRESSION));
+        let appended = Arc::new(watch::channel(()).0);
+
+        Self { messages, compression, appended }
+    }
 
-        Self { messages }
+    /// Notifies on every append, the log has to be read again to find out what has been appended
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.appended.subscribe()
+    }
+
+    /// Sets the codec for the payloads appended from now on, the stored ones are kept as is
+    pub fn set_compression(&self, codec: Option<Codec>) {
+        let mut compression = self.compression.write().unwrap();
+        if *compression != codec {
+            log::info!("Payload compression switched to {codec:?}");
+            *compression = codec;
+        }
+    }
+
+    pub fn compression(&self) -> Option<Codec> {
+        *self.compression.read().unwrap()
     }
 
     pub async fn add(&self, msg: Message) {
+        let entry = Entry::store(msg.clone(), self.compression());
         let mut messages = self.messages.lock().await;
 
-        messages.push(msg.clone());
+        messages.push(entry);
+        self.appended.send_replace(());
         log::info!("{:?} appended", msg)
     }
 
-    pub async fn get_all(&self) -> Vec<Message> {
+    /// Appends the messages under a single lock, so a reader sees either all of them or none
+    pub async fn add_all(&self, msgs: Vec<Message>) {
+        let codec = self.compression();
+        let entries = msgs
+            .into_iter()
+            .map(|msg| Entry::store(msg, codec))
+            .collect::<Vec<_>>();
+        let n = entries.len();
+        let mut messages = self.messages.lock().await;
+
+        entries.into_iter().for_each(|entry| messages.push(entry));
+        self.appended.send_replace(());
+        log::info!("Batch of {n} messages appended")
+    }
+
+    /// The expired messages are hidden from every read
+    pub async fn get(&self, id: &str) -> Option<Message> {
+        let now = timestamp_ms();
+        let messages = self.messages.lock().await;
+
+        messages.by_id
+            .get(id)
+            .map(|i| &messages.log[*i])
+            .filter(|entry| !entry.message.expired(now))
+            .and_then(|entry| entry.load().ok())
+    }
+
+    pub async fn get_at(&self, order: u32) -> Option<Message> {
+        let now = timestamp_ms();
+        let messages = self.messages.lock().await;
+
+        messages.by_order
+            .get(&order)
+            .map(|i| &messages.log[*i])
+            .filter(|entry| !entry.message.expired(now))
+            .and_then(|entry| entry.load().ok())
+    }
+
+    /// The same as `get_at` with an expired message returned as well, `None` only if the ordering is not in the log
+    pub async fn slot(&self, order: u32) -> Option<Message> {
+        let messages = self.messages.lock().await;
+
+        messages.by_order.get(&order).and_then(|i| messages.log[*i].load().ok())
+    }
+
+    /// Returns up to `limit` messages starting from the ordering `from`, sorted by the ordering.
+    /// Only the returned entries are loaded, the rest of the log is not copied
+    pub async fn range(&self, from: u32, limit: usize) -> Vec<Message> {
+        let now = timestamp_ms();
+        let messages = self.messages.lock().await;
+
+        messages.by_order
+            .range(from..)
+            .map(|(_, i)| &messages.log[*i])
+            .filter(|entry| !entry.message.expired(now))
+            .take(limit)
+            .filter_map(|entry| entry.load().ok())
+            .collect()
+    }
+
+    /// The same as `range` with the expired messages in place, so a contiguous reader can tell them from a gap
+    pub(crate) async fn slots(&self, from: u32, limit: usize) -> Vec<Message> {
         let messages = self.messages.lock().await;
 
-        messages.clone()
+        messages.by_order
+            .range(from..)
+            .take(limit)
+            .filter_map(|(_, i)| messages.log[*i].load().ok())
+            .collect()
+    }
+
+    /// The highest ordering in the log
+    pub async fn head(&self) -> u32 {
+        let messages = self.messages.lock().await;
+
+        messages.by_order.last_key_value().map(|(order, _)| *order).unwrap_or_default()
+    }
+
+    /// Returns the digest and the count of entries within the ordering range `from..=to`,
+    /// taken in the order they were appended to the log. An expired message counts with an empty content,
+    /// whether it has been reclaimed or not, a corrupted one is left out, so the range diverges and gets repaired
+    pub async fn digest(&self, from: u32, to: u32, now: u64) -> (u64, u32) {
+        let messages = self.messages.lock().await;
+        let range = messages.log
+            .iter()
+            .filter(|entry| (from..=to).contains(&entry.message.order))
+            .filter_map(|entry| match entry.message.expired(now) {
+                true => Some((entry.message.order, vec![])),
+                false => entry.load().ok().map(|message| (message.order, message.content)),
+            })
+            .collect::<Vec<_>>();
+
+        (range_digest(range.iter().map(|(order, content)| (*order, content.as_slice()))), range.len() as u32)
+    }
+
+    /// Drops the payloads of the expired messages and returns their count. The entries stay in the log,
+    /// so the head, the ordering slots and the replication state are not affected
+    pub async fn sweep(&self, now: u64) -> usize {
+        let mut messages = self.messages.lock().await;
+        let mut reclaimed = 0;
+        for entry in messages.log.iter_mut().filter(|entry| !entry.reclaimed && entry.message.expired(now)) {
+            entry.reclaim();
+            reclaimed += 1;
+        }
+
+        reclaimed
+    }
+
+    /// Drops every entry within the ordering range `from..=to` and puts the `replacement`
+    /// right before the first entry following the range
+    pub async fn replace_range(&self, from: u32, to: u32, replacement: Vec<Message>) {
+        let codec = self.compression();
+        let replacement = replacement
+            .into_iter()
+            .map(|msg| Entry::store(msg, codec))
+            .collect::<Vec<_>>();
+        let mut messages = self.messages.lock().await;
+
+        messages.log.retain(|entry| !(from..=to).contains(&entry.message.order));
+        let at = messages.log
+            .iter()
+            .position(|entry| entry.message.order > to)
+            .unwrap_or(messages.log.len());
+        messages.log.splice(at..at, replacement);
+        messages.reindex();
+        self.appended.send_replace(());
+        log::info!("Messages within ordering range [{from}, {to}] replaced")
+    }
+
+    pub async fn metrics(&self) -> StorageMetrics {
+        let messages = self.messages.lock().await;
+
+        StorageMetrics::new(
+            self.compression(),
+            messages.log.iter().map(|entry| (entry.raw_len, entry.compressed.as_ref().map(|(_, payload)| payload.len()))),
+        )
     }
 }
 
@@ -39,6 +451,39 @@ impl From<&MessageLog> for MessageLog {
     fn from(log: &MessageLog) -> Self {
         Self {
             messages: log.messages.clone(),
+            compression: log.compression.clone(),
+            appended: log.appended.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_once_the_ttl_has_passed() {
+        assert!(!expired(1000, Some(500), 1499));
+        assert!(expired(1000, Some(500), 1500));
+    }
+
+    #[test]
+    fn never_expires_without_a_ttl() {
+        assert!(!expired(1000, None, u64::MAX));
+    }
+
+    #[test]
+    fn digest_depends_on_the_entry_placement() {
+        let digest = range_digest([(1, &b"ab"[..]), (2, &b"c"[..])]);
+
+        assert_eq!(digest, range_digest([(1, &b"ab"[..]), (2, &b"c"[..])]));
+        assert_ne!(digest, range_digest([(2, &b"c"[..]), (1, &b"ab"[..])]));
+        assert_ne!(digest, range_digest([(1, &b"a"[..]), (2, &b"bc"[..])]));
+        assert_ne!(digest, range_digest([(1, &b"ab"[..])]));
+    }
+
+    #[test]
+    fn does_not_overflow_on_a_huge_ttl() {
+        assert!(!expired(1000, Some(u64::MAX), u64::MAX - 1));
+    }
+}
@@ -0,0 +1,97 @@
+use std::env;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::common::message::{Message, MessageLog};
+
+lazy_static! {
+    pub static ref READ_PAGE_SIZE: usize = env::var("READ_PAGE_SIZE")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(100);
+    pub static ref READ_PAGE_SIZE_MAX: usize = env::var("READ_PAGE_SIZE_MAX")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(1000);
+}
+
+const CURSOR_PREFIX: &str = "order:";
+
+
+/// `?from_order=&limit=` or `?cursor=&limit=`, the cursor takes precedence
+#[derive(Debug, Deserialize)]
+pub struct ReadQuery {
+    pub from_order: Option<u32>,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page {
+    pub messages: Vec<Message>,
+    pub next_cursor: Option<String>,
+    pub head: u32,
+}
+
+/// An opaque pointer to the next page, so the clients do not depend on how the pages are built
+pub struct Cursor;
+
+impl Cursor {
+
+    pub fn encode(order: u32) -> String {
+        URL_SAFE_NO_PAD.encode(format!("{CURSOR_PREFIX}{order}"))
+    }
+
+    pub fn decode(cursor: &str) -> Option<u32> {
+        let raw = URL_SAFE_NO_PAD.decode(cursor).ok()?;
+        String::from_utf8(raw).ok()?
+            .strip_prefix(CURSOR_PREFIX)?
+            .parse()
+            .ok()
+    }
+}
+
+impl ReadQuery {
+
+    /// Reads a single page of the log, the next cursor is returned only if there are more messages
+    pub async fn read(&self, log: &MessageLog) -> Result<Page, String> {
+        let from = match &self.cursor {
+            Some(cursor) => Cursor::decode(cursor).ok_or(format!("Invalid cursor `{cursor}`"))?,
+            None => self.from_order.unwrap_or_default(),
+        };
+        let limit = self.limit.unwrap_or(*READ_PAGE_SIZE).clamp(1, *READ_PAGE_SIZE_MAX);
+
+        let head = log.head().await;
+        let mut messages = log.range(from, limit + 1).await;
+        let next_cursor = match messages.len() > limit {
+            true => messages.pop().map(|next| Cursor::encode(next.order)),
+            false => None,
+        };
+
+        Ok(Page { messages, next_cursor, head })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_an_encoded_cursor() {
+        for order in [0, 1, 101, u32::MAX] {
+            assert_eq!(Cursor::decode(&Cursor::encode(order)), Some(order));
+        }
+    }
+
+    #[test]
+    fn rejects_a_malformed_cursor() {
+        assert_eq!(Cursor::decode("zz"), None);
+        assert_eq!(Cursor::decode("not base64!"), None);
+        assert_eq!(Cursor::decode(&URL_SAFE_NO_PAD.encode("offset:1")), None);
+        assert_eq!(Cursor::decode(&URL_SAFE_NO_PAD.encode("order:-1")), None);
+        assert_eq!(Cursor::decode(&URL_SAFE_NO_PAD.encode("order:4294967296")), None);
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::events::Tail;
+use crate::common::message::{timestamp_ms, Message, MessageLog};
+
+/// The internal topic the lease changes are appended to, so they are replicated like messages
+pub const LEASES_TOPIC: &str = "__leases";
+
+/// A message of a topic partition: the topic, the partition and the ordering
+type Slot = (String, u32, u32);
+
+
+/// `{group}/leases/{lease_id}` path segments
+#[derive(Debug, Deserialize)]
+pub struct LeasePath {
+    pub group: String,
+    pub lease_id: String,
+}
+
+/// A message handed out to a single consumer of a group until it is acknowledged or the lease expires
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lease {
+    pub lease_id: String,
+    pub topic: String,
+    pub partition: u32,
+    pub order: u32,
+    pub expires_at_ms: u64,  // the message becomes available again after it, ms since the UNIX epoch
+    pub deliveries: u32,     // how many times the message has been leased
+}
+
+impl Lease {
+    fn slot(&self) -> Slot {
+        (self.topic.clone(), self.partition, self.order)
+    }
+
+    pub fn expired(&self, now: u64) -> bool {
+        self.expires_at_ms <= now
+    }
+}
+
+/// A change of the work queue state, the content of a message of the leases topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LeaseEvent {
+    Leased { group: String, lease: Lease },
+    Acked { group: String, lease_id: String },
+    Nacked { group: String, lease_id: String },
+}
+
+/// The work queue state of a topic partition for a consumer group
+#[derive(Debug, Default)]
+struct PartitionQueue {
+    // the low-water mark, every ordering up to it is acknowledged or can never be delivered
+    acked_up_to: u32,
+    // the acknowledged orderings above the low-water mark
+    acked: BTreeSet<u32>,
+    // the lease ID of every leased message, an expired lease is kept until the message is leased again
+    leased: BTreeMap<u32, String>,
+}
+
+impl PartitionQueue {
+    fn acked(&self, order: u32) -> bool {
+        order <= self.acked_up_to || self.acked.contains(&order)
+    }
+
+    /// Marks the message settled and moves the low-water mark over the contiguous settled ones
+    fn ack(&mut self, order: u32) {
+        if order <= self.acked_up_to {
+            return
+        }
+        self.acked.insert(order);
+        while let Some(next) = self.acked_up_to.checked_add(1).filter(|next| self.acked.remove(next)) {
+            self.acked_up_to = next;
+        }
+    }
+}
+
+/// The work queue state of a consumer group
+#[derive(Debug, Default)]
+struct Queue {
+    partitions: HashMap<(String, u32), PartitionQueue>,
+    leases: HashMap<String, Lease>,
+}
+
+impl Queue {
+    fn partition(&mut self, topic: &str, partition: u32) -> &mut PartitionQueue {
+        self.partitions.entry((topic.to_string(), partition)).or_default()
+    }
+}
+
+/// The work queues of every consumer group, materialized from the leases topic
+#[derive(Debug, Clone, Default)]
+pub struct WorkQueues(Arc<RwLock<HashMap<String, Queue>>>);
+
+impl WorkQueues {
+
+    pub fn apply(&self, event: &LeaseEvent) {
+        let mut queues = self.0.write().unwrap();
+        match event {
+            LeaseEvent::Leased { group, lease } => {
+                let queue = queues.entry(group.clone()).or_default();
+                let leased = &mut queue.partition(&lease.topic, lease.partition).leased;
+                if let Some(previous) = leased.insert(lease.order, lease.lease_id.clone()) {
+                    queue.leases.remove(&previous);
+                }
+                queue.leases.insert(lease.lease_id.clone(), lease.clone());
+            }
+            LeaseEvent::Acked { group, lease_id } => {
+                let queue = queues.entry(group.clone()).or_default();
+                if let Some(lease) = queue.leases.remove(lease_id) {
+                    let partition = queue.partition(&lease.topic, lease.partition);
+                    partition.leased.remove(&lease.order);
+                    partition.ack(lease.order);
+                }
+            }
+            // expired rather than dropped, so the deliveries are counted on
+            LeaseEvent::Nacked { group, lease_id } => {
+                let queue = queues.entry(group.clone()).or_default();
+                if let Some(lease) = queue.leases.get_mut(lease_id) {
+                    lease.expires_at_ms = 0;
+                }
+            }
+        }
+    }
+
+    fn apply_message(&self, message: &Message) {
+        match serde_json::from_slice::<LeaseEvent>(&message.content) {
+            Ok(event) => {
+                log::debug!("Lease change ({}) applied - {event:?}", message.order);
+                self.apply(&event)
+            }
+            Err(e) => log::warn!("Invalid lease change ({}) skipped - {e}", message.order),
+        }
+    }
+
+    /// Applies the lease changes of the leases topic log in the ordering sequence as they are replicated
+    pub async fn follow(self, log: MessageLog) {
+        let mut tail = Tail::new(&log, 1, true);
+        while let Some(message) = tail.next().await {
+            self.apply_message(&message);
+        }
+    }
+
+    /// The first message of the partition after the ordering `after` and up to `head` that is neither acknowledged
+    /// nor leased by the group, with the number of its previous deliveries
+    pub fn next_available(&self, group: &str, topic: &str, partition: u32, after: u32, head: u32) -> Option<(u32, u32)> {
+        let queues = self.0.read().unwrap();
+        let (queue, state) = match queues.get(group).and_then(|queue| {
+            queue.partitions.get(&(topic.to_string(), partition)).map(|state| (queue, state))
+        }) {
+            Some(found) => found,
+            None => return (after < head).then_some((after + 1, 0)),
+        };
+        let now = timestamp_ms();
+
+        (after.max(state.acked_up_to).saturating_add(1)..=head)
+            .filter(|order| !state.acked(*order))
+            .map(|order| (order, state.leased.get(&order).and_then(|lease_id| queue.leases.get(lease_id))))
+            .find(|(_, lease)| !lease.is_some_and(|lease| !lease.expired(now)))
+            .map(|(order, lease)| (order, lease.map(|lease| lease.deliveries).unwrap_or_default()))
+    }
+
+    /// Moves the low-water mark of the group over a message that can never be delivered, e.g. an expired one,
+    /// so the following lookups start after it
+    pub fn skip(&self, group: &str, topic: &str, partition: u32, order: u32) {
+        let mut queues = self.0.write().unwrap();
+        queues.entry(group.to_string()).or_default().partition(topic, partition).ack(order);
+    }
+
+    pub fn lease(&self, group: &str, lease_id: &str) -> Option<Lease> {
+        self.0.read().unwrap().get(group)?.leases.get(lease_id).cloned()
+    }
+
+    /// The unexpired leases of the group
+    pub fn leases(&self, group: &str) -> Vec<Lease> {
+        let now = timestamp_ms();
+        let queues = self.0.read().unwrap();
+        let mut leases = queues
+            .get(group)
+            .map(|queue| queue.leases.values().filter(|lease| !lease.expired(now)).cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        leases.sort_by_key(Lease::slot);
+
+        leases
+    }
+}
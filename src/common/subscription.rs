@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use std::env;
+
+use actix_web::{web, web::Data, HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, CloseReason, Message as Frame, MessageStream, Session};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio::time::{sleep_until, Instant};
+
+use crate::common::events::{self, Tail};
+use crate::common::message::{Message, MessageLog};
+use crate::common::topics::{TopicLog, TopicLogs};
+
+lazy_static! {
+    static ref WS_SUBSCRIBER_WINDOW: u32 = env::var("WS_SUBSCRIBER_WINDOW")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(100);
+    static ref WS_ACK_TIMEOUT_MS: Duration = Duration::from_millis(
+        env::var("WS_ACK_TIMEOUT_MS")
+            .unwrap_or_default()
+            .parse()
+            .unwrap_or(30000)
+    );
+}
+
+
+/// Messages to be delivered, an empty filter passes everything.
+/// A topic or a partition switches the subscription to the log of that partition, the first one of a topic by default
+#[derive(Debug, Default, Deserialize)]
+pub struct Filter {
+    pub topic: Option<String>,
+    pub partition: Option<u32>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+impl Filter {
+
+    /// Every header of the filter has to be present in the message with the same value
+    fn matches(&self, message: &Message) -> bool {
+        self.headers.iter().all(|(key, value)| message.headers.get(key) == Some(value))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Command {
+    /// (Re)starts the subscription from the ordering, the last acknowledged one + 1 to resume
+    Subscribe {
+        from_order: Option<u32>,
+        #[serde(default)]
+        filter: Filter,
+        window: Option<u32>,
+    },
+    /// Confirms every delivered message up to the ordering, which frees the window
+    Ack { order: u32 },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Event<'a> {
+    Subscribed { from_order: u32, window: u32 },
+    Message { message: &'a Message },
+    Error { message: String },
+}
+
+struct Subscription {
+    tail: Tail,
+    filter: Filter,
+    window: u32,
+    // orderings delivered and not acknowledged yet
+    in_flight: Vec<u32>,
+    // since when the window has been full
+    stalled: Option<Instant>,
+}
+
+impl Subscription {
+
+    fn ack(&mut self, order: u32) {
+        self.in_flight.retain(|delivered| *delivered > order);
+        // a stale or repeated ack frees nothing, so the slow consumer deadline keeps running
+        if self.has_room() {
+            self.stalled = None;
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        (self.in_flight.len() as u32) < self.window
+    }
+}
+
+async fn send(session: &mut Session, event: &Event<'_>) -> bool {
+    let text = serde_json::to_string(event).unwrap_or_default();
+    session.text(text).await.is_ok()
+}
+
+/// Upgrades the request to a WebSocket, the subscriber receives the log messages in the ordering sequence,
+/// at most its window of them unacknowledged
+pub fn subscribe(topic: &TopicLog, req: &HttpRequest, body: web::Payload, contiguous: bool) -> HttpResponse {
+    let logs = req.app_data::<Data<TopicLogs>>().map(|logs| TopicLogs::clone(logs)).unwrap_or_default();
+    match actix_ws::handle(req, body) {
+        Ok((response, session, frames)) => {
+            let log = MessageLog::from(&topic.log);
+            actix_web::rt::spawn(serve(log, topic.name.clone(), logs, session, frames, contiguous));
+            response
+        }
+        Err(e) => HttpResponse::from_error(e),
+    }
+}
+
+async fn serve(
+    log: MessageLog,
+    topic: String,
+    logs: TopicLogs,
+    mut session: Session,
+    mut frames: MessageStream,
+    contiguous: bool,
+) {
+    let mut subscription: Option<Subscription> = None;
+    let close = |description: &str| Some(CloseReason { code: CloseCode::Policy, description: Some(description.to_string()) });
+
+    let reason = loop {
+        let (deliver, deadline) = match &subscription {
+            Some(sub) => (sub.has_room(), sub.stalled.map(|since| since + *WS_ACK_TIMEOUT_MS)),
+            None => (false, None),
+        };
+        select! {
+            frame = frames.recv() => match frame {
+                Some(Ok(Frame::Text(text))) => match serde_json::from_str::<Command>(&text) {
+                    Ok(Command::Subscribe { from_order, filter, window }) => {
+                        let log = match (&filter.topic, filter.partition) {
+                            (None, None) => MessageLog::from(&log),
+                            (name, partition) => {
+                                let (name, partition) = (name.as_deref().unwrap_or(&topic), partition.unwrap_or_default());
+                                match logs.get(name, partition) {
+                                    Some(log) => log,
+                                    None => {
+                                        let message = format!("No partition ({partition}) of topic `{name}`");
+                                        if !send(&mut session, &Event::Error { message }).await { break None }
+                                        continue
+                                    }
+                                }
+                            }
+                        };
+                        let from = events::start(&log, from_order).await;
+                        let window = window.unwrap_or(*WS_SUBSCRIBER_WINDOW).clamp(1, *WS_SUBSCRIBER_WINDOW);
+                        log::info!("WebSocket subscription from ordering ({from}), window - {window}, contiguous - {contiguous}");
+                        subscription = Some(Subscription {
+                            tail: Tail::new(&log, from, contiguous),
+                            filter,
+                            window,
+                            in_flight: vec![],
+                            stalled: None,
+                        });
+                        if !send(&mut session, &Event::Subscribed { from_order: from, window }).await { break None }
+                    }
+                    Ok(Command::Ack { order }) => {
+                        if let Some(sub) = subscription.as_mut() {
+                            sub.ack(order);
+                        }
+                    }
+                    Err(e) => {
+                        if !send(&mut session, &Event::Error { message: format!("Invalid command - {e}") }).await { break None }
+                    }
+                },
+                Some(Ok(Frame::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() { break None }
+                }
+                Some(Ok(Frame::Close(reason))) => break reason,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    log::warn!("WebSocket protocol error - {e}");
+                    break None
+                }
+                None => break None,
+            },
+            message = async { subscription.as_mut()?.tail.next().await }, if deliver => {
+                let (Some(sub), Some(message)) = (subscription.as_mut(), message) else { break None };
+                if !sub.filter.matches(&message) {
+                    continue
+                }
+                sub.in_flight.push(message.order);
+                if !sub.has_room() {
+                    sub.stalled = Some(Instant::now());
+                }
+                if !send(&mut session, &Event::Message { message: &message }).await { break None }
+            },
+            _ = async { sleep_until(deadline.unwrap_or_else(Instant::now)).await }, if deadline.is_some() => {
+                log::warn!("WebSocket subscriber has not acknowledged its window within {} ms, closing", WS_ACK_TIMEOUT_MS.as_millis());
+                break close("The subscriber is too slow to acknowledge the messages")
+            },
+        }
+    };
+
+    let _ = session.close(reason).await;
+    log::info!("WebSocket subscription closed");
+}
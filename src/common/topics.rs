@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::env;
+use std::future::{ready, Future, Ready};
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use actix_web::{dev::Payload, error::InternalError, web::Data, FromRequest, HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::common::message::{timestamp_ms, MessageLog};
+use crate::common::utils::ErrorBody;
+
+/// The topic of the messages written without one, it cannot be deleted
+pub const DEFAULT_TOPIC: &str = "default";
+
+/// The prefix of the internal topics, they cannot be created, deleted or written to by the clients
+const INTERNAL_PREFIX: &str = "__";
+
+const NAME_MAX_LEN: usize = 64;
+
+/// The most partitions a topic can have
+pub const MAX_PARTITIONS: u32 = 256;
+
+lazy_static! {
+    /// How often the payloads of the expired messages are reclaimed on every node
+    pub static ref TTL_SWEEP_INTERVAL_MS: u64 = env::var("TTL_SWEEP_INTERVAL_MS")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(10000);
+}
+
+
+/// Validates a topic or a consumer group name, `kind` names it in the error
+pub fn validate_name(kind: &str, name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= NAME_MAX_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    match valid {
+        true => Ok(()),
+        false => Err(format!(
+            "Invalid {kind} name `{name}`, expected up to {NAME_MAX_LEN} letters, digits, `-`, `_` or `.`"
+        )),
+    }
+}
+
+pub fn is_internal(name: &str) -> bool {
+    name.starts_with(INTERNAL_PREFIX)
+}
+
+/// The partition logs of the topics by name, shared by the replication and the read APIs
+#[derive(Debug, Clone, Default)]
+pub struct TopicLogs(Arc<RwLock<HashMap<String, Vec<MessageLog>>>>);
+
+impl TopicLogs {
+
+    pub fn get(&self, name: &str, partition: u32) -> Option<MessageLog> {
+        self.0.read().unwrap().get(name)?.get(partition as usize).map(MessageLog::from)
+    }
+
+    /// The logs of every partition of the topic
+    pub fn partitions(&self, name: &str) -> Option<Vec<MessageLog>> {
+        self.0.read().unwrap().get(name).map(|logs| logs.iter().map(MessageLog::from).collect())
+    }
+
+    pub fn insert(&self, name: &str, logs: &[MessageLog]) {
+        self.0.write().unwrap().insert(name.to_string(), logs.iter().map(MessageLog::from).collect());
+    }
+
+    pub fn remove(&self, name: &str) -> Option<Vec<MessageLog>> {
+        self.0.write().unwrap().remove(name)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.0.read().unwrap().keys().cloned().collect::<Vec<_>>();
+        names.sort();
+
+        names
+    }
+
+    /// Reclaims the expired messages of every partition periodically, the logs of the topics created later included
+    pub async fn sweep(self) {
+        sweeper(|now| {
+            let logs = self.0.read().unwrap().values().flatten().map(MessageLog::from).collect::<Vec<_>>();
+            async move {
+                let mut reclaimed = 0;
+                for log in logs {
+                    reclaimed += log.sweep(now).await;
+                }
+                reclaimed
+            }
+        }).await
+    }
+}
+
+/// Runs a reclamation pass of the expired messages every `TTL_SWEEP_INTERVAL_MS`, the pass gets the expiry cutoff
+/// of the tick and returns the count of the reclaimed messages
+pub async fn sweeper<F, R>(mut pass: F)
+where
+    F: FnMut(u64) -> R,
+    R: Future<Output = usize>,
+{
+    let mut ticks = interval(Duration::from_millis(*TTL_SWEEP_INTERVAL_MS));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let reclaimed = pass(timestamp_ms()).await;
+        if reclaimed > 0 {
+            log::info!("{reclaimed} expired message(s) reclaimed");
+        }
+    }
+}
+
+/// The `{partition}` path segment, extracted by name like the ones below
+#[derive(Debug, Deserialize)]
+struct PartitionPath {
+    partition: Option<u32>,
+}
+
+/// The `{id}` path segment of a message resource, extracted by name to coexist with the `{topic}` one
+#[derive(Debug, Deserialize)]
+pub struct MessageId {
+    pub id: String,
+}
+
+/// The `{order}` path segment of a message resource
+#[derive(Debug, Deserialize)]
+pub struct MessageAt {
+    pub order: u32,
+}
+
+/// The log of the topic partition addressed by the `{topic}` and `{partition}` path segments,
+/// the default topic if there is none and its first partition if the partition is not given
+pub struct TopicLog {
+    pub name: String,
+    pub partition: Option<u32>,
+    pub log: MessageLog,
+}
+
+impl Deref for TopicLog {
+    type Target = MessageLog;
+
+    fn deref(&self) -> &Self::Target {
+        &self.log
+    }
+}
+
+impl FromRequest for TopicLog {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let name = req.match_info().get("topic").unwrap_or(DEFAULT_TOPIC).to_string();
+        let partition = match req.match_info().load::<PartitionPath>() {
+            Ok(path) => path.partition,
+            Err(e) => return ready(Err(InternalError::from_response(
+                e.to_string(),
+                HttpResponse::BadRequest().json(ErrorBody::new(format!("Invalid partition - {e}")))
+            ).into())),
+        };
+        let log = req.app_data::<Data<TopicLogs>>().and_then(|logs| logs.get(&name, partition.unwrap_or_default()));
+
+        ready(match log {
+            Some(log) => Ok(Self { name, partition, log }),
+            None => {
+                let message = match partition {
+                    Some(partition) => format!("No partition ({partition}) of topic `{name}`"),
+                    None => format!("No topic `{name}`"),
+                };
+                Err(InternalError::from_response(message.clone(), HttpResponse::NotFound().json(ErrorBody::new(message))).into())
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_letters_digits_and_separators() {
+        assert!(validate_name("topic", "orders").is_ok());
+        assert!(validate_name("topic", "orders-v2_eu.west").is_ok());
+        assert!(validate_name("topic", &"a".repeat(NAME_MAX_LEN)).is_ok());
+    }
+
+    #[test]
+    fn rejects_empty_long_and_unsafe_names() {
+        assert!(validate_name("topic", "").is_err());
+        assert!(validate_name("topic", &"a".repeat(NAME_MAX_LEN + 1)).is_err());
+        assert!(validate_name("topic", "orders/0").is_err());
+        assert!(validate_name("topic", "orders eu").is_err());
+        assert!(validate_name("group", "zakázky").is_err());
+    }
+
+    #[test]
+    fn internal_names_are_reserved_by_the_prefix() {
+        assert!(is_internal("__consumer_offsets"));
+        assert!(!is_internal("_orders"));
+    }
+}
@@ -1,6 +1,10 @@
 use std::env;
 
 use log4rs;
+use serde::Serialize;
+
+pub const FNV1A_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV1A_PRIME: u64 = 0x100000001b3;
 
 pub fn init_logger() {
     let log_path = env::var("HOME").unwrap_or(String::from("."));
@@ -8,3 +12,39 @@ pub fn init_logger() {
 
     log4rs::init_file(log_file, Default::default()).unwrap();
 }
+
+/// 64-bit FNV-1a, unlike the std hashers its output is specified, so it never changes with the Rust version
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    fnv1a_extend(FNV1A_OFFSET_BASIS, bytes)
+}
+
+/// Continues an FNV-1a `hash` with more bytes, so several fields are hashed without being concatenated first
+pub fn fnv1a_extend(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV1A_PRIME))
+}
+
+/// The body of a refused request, the same `{"message": ..., "status": false}` shape as of the `master` write responses
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    message: String,
+    status: bool,
+}
+
+impl ErrorBody {
+    pub fn new(message: String) -> Self {
+        Self { message, status: false }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fnv1a_matches_the_reference_values() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x85944171f73967e8);
+        assert_eq!(fnv1a_extend(fnv1a(b"foo"), b"bar"), fnv1a(b"foobar"));
+    }
+}
@@ -1,4 +1,5 @@
 use lazy_static::lazy_static;
+use std::collections::HashMap;
 use std::env;
 
 pub mod common {
@@ -6,6 +7,13 @@ pub mod common {
     pub mod utils;
     pub mod retry;
     pub mod heartbeats;
+    pub mod compression;
+    pub mod pagination;
+    pub mod events;
+    pub mod subscription;
+    pub mod topics;
+    pub mod groups;
+    pub mod queues;
 }
 
 lazy_static! {
@@ -34,4 +42,11 @@ lazy_static! {
         .unwrap_or_default()
         .parse()
         .unwrap_or(1);
+    // `key=value` pairs separated by commas, e.g. `zone=eu-1,rack=a`
+    pub static ref NODE_LABELS: HashMap<String, String> = env::var("NODE_LABELS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|label| label.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
 }
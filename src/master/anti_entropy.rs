@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::env;
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tonic::{Request, Status};
+use tonic::transport::Channel;
+
+use replog::common::message::{range_digest, timestamp_ms};
+use crate::topics::{batch_range, Partition};
+use crate::replicator_client::replicator::{DigestRange, RangeRepair};
+use crate::replicator_client::replicator::replicator_client::ReplicatorClient;
+
+lazy_static! {
+    pub static ref ANTI_ENTROPY_INTERVAL_MS: Duration = Duration::from_millis(
+        env::var("ANTI_ENTROPY_INTERVAL_MS")
+            .unwrap_or_default()
+            .parse()
+            .unwrap_or(60000)
+    );
+    static ref ANTI_ENTROPY_LEAF_SIZE: u32 = env::var("ANTI_ENTROPY_LEAF_SIZE")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(16);
+}
+
+pub type Reports = Arc<Mutex<HashMap<String, AntiEntropyReport>>>;
+
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AntiEntropyReport {
+    pub checked_up_to: u32,
+    pub node_ordering: u32,
+    pub repaired: Vec<(u32, u32)>,
+    pub finished_at_ms: u128,
+}
+
+/// Compares the master stash of a partition with the log of a single node over the ordering range `1..=up_to`
+/// by halving the divergent ranges, Merkle-style, until they are small enough to be resent whole
+pub struct Reconciler<'a> {
+    host: &'a str,
+    client: &'a mut ReplicatorClient<Channel>,
+    partition: &'a Partition,
+    repaired: Vec<(u32, u32)>,
+    node_ordering: u32,
+    // the expiry cutoff of the whole pass, sent to the node, so both sides hash the same messages as expired
+    now: u64,
+}
+
+impl<'a> Reconciler<'a> {
+
+    pub fn new(host: &'a str, client: &'a mut ReplicatorClient<Channel>, partition: &'a Partition) -> Self {
+        Self { host, client, partition, repaired: vec![], node_ordering: 0, now: timestamp_ms() }
+    }
+
+    pub async fn run(mut self, up_to: u32) -> Result<AntiEntropyReport, Status> {
+        if up_to > 0 && self.diverges(1, up_to).await? {
+            self.reconcile(1, up_to).await?;
+        }
+
+        Ok(AntiEntropyReport {
+            checked_up_to: up_to,
+            node_ordering: self.node_ordering,
+            repaired: self.repaired,
+            finished_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+        })
+    }
+
+    fn reconcile(&mut self, from: u32, to: u32) -> BoxFuture<'_, Result<(), Status>> {
+        async move {
+            if to - from < *ANTI_ENTROPY_LEAF_SIZE {
+                return self.repair(from, to).await
+            }
+
+            let mid = from + (to - from) / 2;
+            let left = self.diverges(from, mid).await?;
+            let right = self.diverges(mid + 1, to).await?;
+            if !left && !right {
+                // both halves are intact, so only the relative placement of their entries differs
+                return self.repair(from, to).await
+            }
+            if left { self.reconcile(from, mid).await? }
+            if right { self.reconcile(mid + 1, to).await? }
+
+            Ok(())
+        }.boxed()
+    }
+
+    async fn diverges(&mut self, from: u32, to: u32) -> Result<bool, Status> {
+        let expected = {
+            // an expired replica counts with an empty content, the same as on the nodes
+            let stash = self.partition.stash.lock().await;
+            let range = stash
+                .range(from..=to)
+                .map(|(order, stashed)| match stashed.replica.expired(self.now) {
+                    true => Ok((*order, vec![])),
+                    false => stashed.load().map(|rep| (*order, rep.content)),
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(corrupted)?;
+            range_digest(range.iter().map(|(order, content)| (*order, content.as_slice())))
+        };
+
+        let actual = self.client
+            .digest(Request::new(DigestRange {
+                from,
+                to,
+                topic: self.partition.topic.clone(),
+                partition: self.partition.index,
+                now: self.now,
+            }))
+            .await?
+            .into_inner();
+        self.node_ordering = actual.ordering;
+
+        Ok(expected != actual.digest)
+    }
+
+    async fn repair(&mut self, from: u32, to: u32) -> Result<(), Status> {
+        let (from, to, replicas) = {
+            let stash = self.partition.stash.lock().await;
+            // the range is widened to the whole batches at its edges, so a node never holds a part of a batch
+            let (from, to) = (batch_range(&stash, from).0, batch_range(&stash, to).1);
+            let replicas = stash
+                .range(from..=to)
+                .map(|(_, stashed)| stashed.load())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(corrupted)?;
+            (from, to, replicas)
+        };
+
+        log::warn!("{}: divergent ordering range [{from}, {to}] detected, repairing...", self.host);
+        self.client
+            .repair(Request::new(RangeRepair {
+                from,
+                to,
+                replicas,
+                topic: self.partition.topic.clone(),
+                partition: self.partition.index,
+            }))
+            .await?;
+        self.repaired.push((from, to));
+
+        Ok(())
+    }
+}
+
+/// The pass is aborted on a corrupted stash, it must not be the source of a repair
+fn corrupted(e: std::io::Error) -> Status {
+    Status::data_loss(format!("Corrupted stash - {e}"))
+}
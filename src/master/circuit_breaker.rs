@@ -14,6 +14,8 @@ use sync_request::SyncClaim;
 use sync_request::sync_request_client::SyncRequestClient;
 
 use crate::SharedReplicator;
+use crate::node_replicator::NodeInfo;
+use replog::common::compression::Codec;
 use replog::common::heartbeats::{HealthChecker, HealthStatus};
 use replog::common::retry::Attempts;
 
@@ -51,7 +53,7 @@ impl CircuitBreaker {
         }
     }
 
-    pub async fn watch(&self, node: Channel, name: String) {
+    pub async fn watch(&self, node: Channel, name: String, codec: Option<Codec>) {
 
         let (tx, mut rx) = channel::<HealthStatus>(10);
         let checker = HealthChecker::new(node.clone(), name.clone());
@@ -80,18 +82,17 @@ impl CircuitBreaker {
             res = hb => match res {
                 Ok(_) => {
                     log::info!("{name}: node seems to be recovered, sending SyncRequest...");
-                    self.initiate_sync(node, name).await;
+                    self.initiate_sync(node, name, codec).await;
                 },
                 Err(e) => log::error!("{name}: error happened during waiting for recovery - {e:?}")
             }
         }
     }
 
-    pub async fn connect(&self, node: Channel, name: String, node_ordering: u32) {
+    pub async fn connect(&self, node: Channel, name: String, info: NodeInfo, announced: HashMap<String, u32>) {
         self.try_unwatch_old(&name).await;
 
-        self.client.add_node(&name, &node).await;
-        self.client.sync_node(&name, &node, node_ordering).await;
+        self.client.add_node(&name, &node, info, &announced).await;
     }
 
     async fn break_(&self, node_name: &str, abort: Sender<()>) {
@@ -103,7 +104,7 @@ impl CircuitBreaker {
         self.client.del_node(node_name).await;
     }
 
-    async fn initiate_sync(&self, node: Channel, name: String) {
+    async fn initiate_sync(&self, node: Channel, name: String, codec: Option<Codec>) {
         // a secondary node itself doesn't know that it has been stall or paused, so
         // the master needs to inform it and request its current state in order to synchronize the message log
         let mut att = Attempts::default();
@@ -111,6 +112,11 @@ impl CircuitBreaker {
         log::info!("{name}: initiating SyncRequest for the node to rejoin and sync...");
         while att.next() {
             let mut client = SyncRequestClient::new(node.clone());
+            if let Some(codec) = codec {
+                client = client
+                    .send_compressed(codec.encoding())
+                    .accept_compressed(codec.encoding());
+            }
             let request = Request::new(SyncClaim {});
             let res = match client.sync(request).await {
                 Ok(_) => { log::info!("{name}: sync request sent"); true }
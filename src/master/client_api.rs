@@ -0,0 +1,233 @@
+use std::pin::Pin;
+
+use futures::{Stream, StreamExt};
+use tonic::{Request, Response, Status, async_trait};
+
+use client::client_server::Client;
+use client::{
+    write_concern, AppendBatchRequest, AppendRequest, AppendResponse, Draft, Item, Message, ReadRequest,
+    ReadResponse, SubscribeRequest, WriteOptions,
+};
+use client::subscribe_request::From as SubscribeFrom;
+use client::write_options::{ExpectedLast, Wtimeout};
+
+use replog::common::events;
+use replog::common::message;
+use replog::common::pagination::ReadQuery;
+use replog::common::topics::DEFAULT_TOPIC;
+use crate::replicator_client::Backpressure;
+use crate::write_concern::{TagQuorum, WriteConcern};
+use crate::writer::{self, Append, Rejection, Writer};
+
+pub mod client {
+    tonic::include_proto!("client");
+}
+
+type MessageStream = Pin<Box<dyn Stream<Item = Result<Message, Status>> + Send>>;
+
+/// An empty topic name stands for the default topic
+fn topic(name: String) -> String {
+    match name.is_empty() {
+        true => DEFAULT_TOPIC.to_string(),
+        false => name,
+    }
+}
+
+
+impl From<message::Message> for Message {
+    fn from(message: message::Message) -> Self {
+        Self {
+            id: message.id,
+            order: message.order,
+            content: message.content,
+            headers: message.headers,
+            content_type: message.content_type,
+            timestamp: message.timestamp,
+            producer_id: message.producer_id.unwrap_or_default(),
+            key: message.key.unwrap_or_default(),
+            redacts: message.redacts.unwrap_or_default(),
+            redacted: message.redacted,
+            ttl_ms: message.ttl_ms.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<Draft> for writer::Draft {
+    fn from(draft: Draft) -> Self {
+        Self {
+            content: draft.content,
+            headers: draft.headers,
+            content_type: (!draft.content_type.is_empty()).then_some(draft.content_type),
+            ttl_ms: (draft.ttl_ms > 0).then_some(draft.ttl_ms),
+        }
+    }
+}
+
+impl From<Rejection> for Status {
+    fn from(rejection: Rejection) -> Self {
+        let message = rejection.to_string();
+        match rejection {
+            Rejection::Empty
+            | Rejection::InvalidConcern(_)
+            | Rejection::InternalTopic(_)
+            | Rejection::AmbiguousPartition(_)
+            | Rejection::InvalidLeaseTimeout(_) => {
+                Status::invalid_argument(message)
+            }
+            Rejection::UnknownTopic(_) | Rejection::UnknownPartition(_) | Rejection::UnknownMessage(_) => {
+                Status::not_found(message)
+            }
+            Rejection::Redacted(_) => Status::failed_precondition(message),
+            Rejection::NoQuorum | Rejection::Backpressure(Backpressure::WindowFull { .. }) => Status::unavailable(message),
+            Rejection::Backpressure(Backpressure::QueueFull { .. }) => Status::resource_exhausted(message),
+            Rejection::Conflict(_) => Status::aborted(message),
+            Rejection::OrderingExhausted(_) => Status::resource_exhausted(message),
+        }
+    }
+}
+
+impl WriteOptions {
+
+    /// `None` if there is no write concern
+    fn append(self, topic: String, key: String, messages: Vec<writer::Draft>, producer_id: String) -> Option<Append> {
+        let wc = match self.wc.and_then(|wc| wc.concern) {
+            Some(write_concern::Concern::Nodes(n)) => WriteConcern::Nodes(n.min(u8::MAX as u32) as u8),
+            Some(write_concern::Concern::Named(name)) => WriteConcern::Named(name),
+            Some(write_concern::Concern::Tags(tags)) => WriteConcern::Tags(
+                tags.tags
+                    .into_iter()
+                    .map(|(tag, quorum)| match quorum.parse() {
+                        Ok(n) => (tag, TagQuorum::Values(n)),
+                        Err(_) => (tag, TagQuorum::Each(quorum)),
+                    })
+                    .collect()
+            ),
+            None => return None,
+        };
+
+        Some(Append {
+            topic,
+            partition: None,
+            key: (!key.is_empty()).then_some(key),
+            messages,
+            producer_id: (!producer_id.is_empty()).then_some(producer_id),
+            wc,
+            wtimeout_ms: self.wtimeout.map(|Wtimeout::WtimeoutMs(ms)| ms),
+            expected_last_order: self.expected_last.map(|ExpectedLast::ExpectedLastOrder(order)| order),
+            ordering: None,
+            redacts: None,
+        })
+    }
+}
+
+/// The client API of the master over gRPC, shares the write pipeline with the REST one
+pub struct ClientApi {
+    writer: Writer,
+}
+
+impl ClientApi {
+
+    pub fn new(writer: Writer) -> Self {
+        Self { writer }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn log(&self, name: String, partition: u32) -> Result<message::MessageLog, Status> {
+        let name = topic(name);
+        self.writer
+            .log(&name, partition)
+            .ok_or_else(|| Status::not_found(format!("No partition ({partition}) of topic `{name}`")))
+    }
+
+    async fn write(&self, append: Append) -> Result<Response<AppendResponse>, Status> {
+        let prepared = self.writer.prepare(&append).await?;
+        let outcome = self.writer.replicate(prepared).await;
+
+        let (status, message, acked) = match outcome.acked {
+            Ok(acked) => (true, String::from("Delivered"), acked),
+            Err(e) => (false, e.to_string(), e.acked().to_vec()),
+        };
+
+        Ok(Response::new(AppendResponse {
+            status,
+            message,
+            items: outcome.items.into_iter().map(|(id, order)| Item { id, order }).collect(),
+            acked,
+            elapsed_ms: outcome.elapsed_ms as u64,
+            partition: outcome.partition,
+        }))
+    }
+}
+
+#[async_trait]
+impl Client for ClientApi {
+
+    async fn append(&self, request: Request<AppendRequest>) -> Result<Response<AppendResponse>, Status> {
+        let request = request.into_inner();
+        log::debug!("Called Append RPC");
+
+        let append = request.options
+            .unwrap_or_default()
+            .append(
+                topic(request.topic),
+                request.key,
+                vec![Draft {
+                    content: request.message,
+                    headers: request.headers,
+                    content_type: request.content_type,
+                    ttl_ms: request.ttl_ms,
+                }.into()],
+                request.producer_id,
+            )
+            .ok_or(Status::invalid_argument("The write concern is required"))?;
+        self.write(append).await
+    }
+
+    async fn append_batch(&self, request: Request<AppendBatchRequest>) -> Result<Response<AppendResponse>, Status> {
+        let request = request.into_inner();
+        log::debug!("Called AppendBatch RPC with {} messages", request.messages.len());
+
+        let append = request.options
+            .unwrap_or_default()
+            .append(
+                topic(request.topic),
+                request.key,
+                request.messages.into_iter().map(writer::Draft::from).collect(),
+                request.producer_id,
+            )
+            .ok_or(Status::invalid_argument("The write concern is required"))?;
+        self.write(append).await
+    }
+
+    async fn read(&self, request: Request<ReadRequest>) -> Result<Response<ReadResponse>, Status> {
+        let request = request.into_inner();
+        log::debug!("Called Read RPC - {request:?}");
+
+        let query = ReadQuery {
+            from_order: Some(request.from_order),
+            limit: (request.limit > 0).then_some(request.limit as usize),
+            cursor: (!request.cursor.is_empty()).then_some(request.cursor),
+        };
+        let log = self.log(request.topic, request.partition)?;
+        let page = query.read(&log).await.map_err(Status::invalid_argument)?;
+
+        Ok(Response::new(ReadResponse {
+            messages: page.messages.into_iter().map(Message::from).collect(),
+            next_cursor: page.next_cursor.unwrap_or_default(),
+            head: page.head,
+        }))
+    }
+
+    type SubscribeStream = MessageStream;
+
+    async fn subscribe(&self, request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let request = request.into_inner();
+        let log = self.log(request.topic, request.partition)?;
+        let from = events::start(&log, request.from.map(|SubscribeFrom::FromOrder(from)| from)).await;
+        log::info!("Streaming the messages from ordering ({from}) over gRPC");
+
+        // the same contiguous tail as of the SSE stream, a message appended out of order is not skipped
+        let messages = events::tail(&log, from, true).map(Message::from).map(Ok);
+        Ok(Response::new(Box::pin(messages)))
+    }
+}
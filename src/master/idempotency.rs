@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::env;
+
+use actix_web::http::StatusCode;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref IDEMPOTENCY_WINDOW_MS: Duration = Duration::from_millis(
+        env::var("IDEMPOTENCY_WINDOW_MS")
+            .unwrap_or_default()
+            .parse()
+            .unwrap_or(600000)
+    );
+}
+
+type Records<T> = Arc<Mutex<HashMap<String, Record<T>>>>;
+
+
+// `fingerprint` is the hash of the request the key has been used with first
+enum Record<T> {
+    Pending { fingerprint: u64, reserved: Option<(StatusCode, T)> },
+    Done { fingerprint: u64, at: Instant, status: StatusCode, body: T },
+}
+
+impl<T> Record<T> {
+    fn fingerprint(&self) -> u64 {
+        match self {
+            Record::Pending { fingerprint, .. } | Record::Done { fingerprint, .. } => *fingerprint,
+        }
+    }
+}
+
+pub enum Claim<T> {
+    New(IdempotencyGuard<T>),
+    InProgress,
+    Replay(StatusCode, T),
+    // the key has been used with another request
+    Mismatch,
+}
+
+/// Remembers the results of the writes by their idempotency keys for `IDEMPOTENCY_WINDOW_MS`
+#[derive(Clone)]
+pub struct IdempotencyCache<T> {
+    records: Records<T>,
+}
+
+impl<T: Clone> Default for IdempotencyCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> IdempotencyCache<T> {
+
+    pub fn new() -> Self {
+        Self { records: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub fn claim(&self, key: &str, fingerprint: u64) -> Claim<T> {
+        let mut records = self.records.lock().unwrap();
+        records.retain(|_, record| match record {
+            Record::Done { at, .. } => at.elapsed() < *IDEMPOTENCY_WINDOW_MS,
+            Record::Pending { .. } => true,
+        });
+
+        match records.get(key) {
+            Some(record) if record.fingerprint() != fingerprint => Claim::Mismatch,
+            Some(Record::Pending { .. }) => Claim::InProgress,
+            Some(Record::Done { status, body, .. }) => Claim::Replay(*status, body.clone()),
+            None => {
+                records.insert(key.to_string(), Record::Pending { fingerprint, reserved: None });
+                Claim::New(IdempotencyGuard {
+                    key: key.to_string(),
+                    fingerprint,
+                    records: self.records.clone(),
+                    done: false,
+                })
+            }
+        }
+    }
+}
+
+/// Holds the claimed key while the write is processed.
+/// Dropped before completion (e.g. the client has disconnected or the write concern has not been satisfied yet),
+/// it either releases the key or, if the message has already got its ordering, keeps the reserved result for the retries
+pub struct IdempotencyGuard<T> {
+    key: String,
+    fingerprint: u64,
+    records: Records<T>,
+    done: bool,
+}
+
+impl<T> IdempotencyGuard<T> {
+
+    pub fn reserve(&self, status: StatusCode, body: T) {
+        let mut records = self.records.lock().unwrap();
+        records.insert(self.key.clone(), Record::Pending { fingerprint: self.fingerprint, reserved: Some((status, body)) });
+    }
+
+    /// Remembers the final result of the write
+    pub fn complete(mut self, status: StatusCode, body: T) {
+        let mut records = self.records.lock().unwrap();
+        let fingerprint = self.fingerprint;
+        records.insert(self.key.clone(), Record::Done { fingerprint, at: Instant::now(), status, body });
+        self.done = true;
+    }
+}
+
+impl<T> Drop for IdempotencyGuard<T> {
+    fn drop(&mut self) {
+        if self.done { return }
+
+        let mut records = self.records.lock().unwrap();
+        if let Some(Record::Pending { fingerprint, reserved: Some((status, body)) }) = records.remove(&self.key) {
+            records.insert(self.key.clone(), Record::Done { fingerprint, at: Instant::now(), status, body });
+        }
+    }
+}
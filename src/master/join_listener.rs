@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
@@ -10,14 +11,22 @@ use join_request::join_request_server::{JoinRequest, JoinRequestServer};
 use join_request::{Ack, NodeState};
 
 use replog::{RPC_DEF_PORT, RPC_SERVER_RECONNECT_DELAY_MS};
+use replog::common::compression::{Codec, PAYLOAD_COMPRESSION};
+use replog::common::topics::DEFAULT_TOPIC;
+use crate::SharedReplicator;
 use crate::circuit_breaker::CircuitBreaker;
+use crate::client_api::ClientApi;
+use crate::client_api::client::client_server::ClientServer;
+use crate::node_replicator::NodeInfo;
+use crate::replicator_client::EPOCH;
 
 pub mod join_request {
     tonic::include_proto!("joinreq");
 }
 
 pub struct JoinListener {
-    cb: Arc<CircuitBreaker>
+    cb: Arc<CircuitBreaker>,
+    replicator: SharedReplicator,
 }
 
 #[async_trait]
@@ -36,6 +45,8 @@ impl JoinRequest for JoinListener {
             format!("http://{}:{}", body.host, *RPC_DEF_PORT)
         };
 
+        let codec = Codec::negotiate(&body.compression);
+        let topics = self.replicator.topic_partitions().await;
         let success = match Endpoint::from_shared(url) {
             Ok(end) => {
                 let host = end.uri().host().unwrap();
@@ -49,13 +60,24 @@ impl JoinRequest for JoinListener {
                     Ok(channel) => {
                         let host = host.to_string();
                         let cb = self.cb.clone();
-                        cb.connect(channel.clone(), host.clone(), body.ordering).await;
-
+                        let mut orderings = body.partitions
+                            .iter()
+                            .map(|state| ((state.topic.clone(), state.partition), state.ordering))
+                            .collect::<HashMap<_, _>>();
+                        orderings.entry((DEFAULT_TOPIC.to_string(), 0)).or_insert(body.ordering);
+                        let info = NodeInfo { orderings, codec, labels: body.labels.clone() };
+
+                        // the actors start once the Ack is on its way, the node takes the epoch and the topics from it
+                        let announced = topics.clone();
                         tokio::spawn(async move {
-                            cb.watch(channel, host).await
+                            cb.connect(channel.clone(), host.clone(), info, announced).await;
+                            cb.watch(channel, host, codec).await
                         });
 
-                        log::info!("Secondary end `{}` connected", end.uri());
+                        log::info!(
+                            "Secondary end `{}` connected, replication compression - {codec:?}, labels - {:?}",
+                            end.uri(), body.labels
+                        );
                         true
                     },
                     Err(e) => {
@@ -70,7 +92,13 @@ impl JoinRequest for JoinListener {
             }
         };
 
-        Ok(Response::new(Ack { success }))
+        Ok(Response::new(Ack {
+            success,
+            compression: Codec::name(codec),
+            payload_compression: Codec::name(*PAYLOAD_COMPRESSION),
+            epoch: EPOCH.clone(),
+            topics,
+        }))
 
     }
 }
@@ -78,15 +106,18 @@ impl JoinRequest for JoinListener {
 
 impl JoinListener {
 
-    pub async fn start(cb: CircuitBreaker) {
+    /// Serves the join requests of the nodes and the client API
+    pub async fn start(cb: CircuitBreaker, replicator: SharedReplicator, client_api: ClientApi) {
 
         let addr = SocketAddr::from(([0,0,0,0], *RPC_DEF_PORT));
 
         let dur = Duration::from_millis(*RPC_SERVER_RECONNECT_DELAY_MS);
         let cb = Arc::new(cb);
+        let client_api = ClientServer::new(client_api);
         loop {
             match Server::builder()
-                .add_service(JoinRequestServer::new(Self { cb: cb.clone() }))
+                .add_service(JoinRequestServer::new(Self { cb: cb.clone(), replicator: replicator.clone() }))
+                .add_service(client_api.clone())
                 .serve(addr)
                 .await {
 
@@ -1,93 +1,958 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
 use std::sync::Arc;
 
 use actix_web::{
-    get, post, web,
-    web::{Data, Json},
-    App, HttpRequest, HttpResponse, HttpServer,
+    delete, get, post, web,
+    http::{header, StatusCode},
+    web::{Data, Json, Path, Query},
+    App, HttpRequest, HttpResponse, HttpServer, Scope,
 };
-use log;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
 
 use join_listener::JoinListener;
-use replicator_client::ReplicatorMultiClient;
+use replicator_client::{
+    Backpressure, ReplicatorMultiClient, WriteConcernError, BACKPRESSURE_RETRY_AFTER_S
+};
 
 use replog::{common, SERVER_DEF_PORT, SERVER_WORKER_NUM};
-use common::message::{Message, MessageLog};
+use common::compression::Codec;
+use common::message::{payload, Message, StorageMetrics, BINARY_CONTENT_TYPE};
+use common::groups::{ConsumerGroups, GroupPath, OffsetCommit, OffsetPath};
+use common::queues::{Lease, LeasePath, WorkQueues};
+use common::topics::{is_internal, validate_name, MessageAt, MessageId, TopicLog, TopicLogs, DEFAULT_TOPIC, MAX_PARTITIONS};
+use common::pagination::ReadQuery;
+use common::events::{self, StreamQuery};
+use common::subscription;
 use crate::circuit_breaker::CircuitBreaker;
+use crate::client_api::ClientApi;
+use crate::idempotency::{Claim, IdempotencyCache, IdempotencyGuard};
+use crate::write_concern::WriteConcern;
+use crate::topics::TOPIC_PARTITIONS;
+use crate::writer::{Append, Draft, Rejection, Writer};
 
 mod join_listener;
 mod replicator_client;
 mod circuit_breaker;
+mod node_replicator;
+mod anti_entropy;
+mod write_concern;
+mod idempotency;
+mod writer;
+mod client_api;
+mod topics;
 
 pub type SharedReplicator = Arc<ReplicatorMultiClient>;
 
 
 #[derive(Debug, Deserialize)]
 pub struct RequestBody {
-    message: String,
-    wc: u8,  // write concern
+    #[serde(flatten, deserialize_with = "message_payload")]
+    message: Vec<u8>,  // a UTF-8 `message` or a base64 `message_base64`
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    content_type: Option<String>,  // `text/plain` by default
+    producer_id: Option<String>,
+    key: Option<String>,  // routes the message to a partition, round-robin if not set
+    ttl_ms: Option<u64>,  // hidden from the reads and reclaimed after it, never expires by default
+    wc: WriteConcern,  // write concern
+    wtimeout_ms: Option<u64>,  // write concern timeout
+    idempotency_key: Option<String>,  // an alternative to the `Idempotency-Key` header
+    expected_last_order: Option<u32>,  // the write is applied only if it is the current head ordering
     __ordering: Option<u32>,
 }
 
-#[derive(Serialize)]
-struct ResponseBody {
+fn message_payload<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    #[derive(Deserialize)]
+    struct Fields {
+        message: Option<String>,
+        message_base64: Option<String>,
+    }
+
+    let fields = Fields::deserialize(deserializer)?;
+    payload::decode(fields.message, fields.message_base64).map_err(de::Error::custom)
+}
+
+/// The options of a raw message write, the body is the payload as is
+#[derive(Debug, Deserialize)]
+struct RawQuery {
+    wc: String,
+    wtimeout_ms: Option<u64>,
+    idempotency_key: Option<String>,
+    expected_last_order: Option<u32>,
+    producer_id: Option<String>,
+    key: Option<String>,
+    ttl_ms: Option<u64>,
+}
+
+/// The write concern of a tombstone
+#[derive(Debug, Deserialize)]
+struct RedactQuery {
+    wc: String,
+    wtimeout_ms: Option<u64>,
+}
+
+/// Either a bare content or a message with the metadata
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum BatchMessage {
+    Content(String),
+    Draft(Draft),
+}
+
+impl From<BatchMessage> for Draft {
+    fn from(message: BatchMessage) -> Self {
+        match message {
+            BatchMessage::Content(content) => Draft::from(content.into_bytes()),
+            BatchMessage::Draft(draft) => draft,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequestBody {
+    messages: Vec<BatchMessage>,
+    producer_id: Option<String>,
+    key: Option<String>,  // the whole batch goes to the partition of the key
+    wc: WriteConcern,
+    wtimeout_ms: Option<u64>,
+    idempotency_key: Option<String>,
+    expected_last_order: Option<u32>,
+}
+
+impl From<&RequestBody> for Append {
+    fn from(request: &RequestBody) -> Self {
+        Self {
+            topic: DEFAULT_TOPIC.to_string(),
+            partition: None,
+            key: request.key.clone(),
+            messages: vec![Draft {
+                content: request.message.clone(),
+                headers: request.headers.clone(),
+                content_type: request.content_type.clone(),
+                ttl_ms: request.ttl_ms,
+            }],
+            producer_id: request.producer_id.clone(),
+            wc: request.wc.clone(),
+            wtimeout_ms: request.wtimeout_ms,
+            expected_last_order: request.expected_last_order,
+            ordering: request.__ordering,
+            redacts: None,
+        }
+    }
+}
+
+impl From<&BatchRequestBody> for Append {
+    fn from(request: &BatchRequestBody) -> Self {
+        Self {
+            topic: DEFAULT_TOPIC.to_string(),
+            partition: None,
+            key: request.key.clone(),
+            messages: request.messages.iter().cloned().map(Draft::from).collect(),
+            producer_id: request.producer_id.clone(),
+            wc: request.wc.clone(),
+            wtimeout_ms: request.wtimeout_ms,
+            expected_last_order: request.expected_last_order,
+            ordering: None,
+            redacts: None,
+        }
+    }
+}
+
+fn default_topic() -> String {
+    DEFAULT_TOPIC.to_string()
+}
+
+/// The last ordering of a topic partition consumed by the group
+#[derive(Debug, Deserialize)]
+struct OffsetBody {
+    #[serde(default = "default_topic")]
+    topic: String,
+    #[serde(default)]
+    partition: u32,
+    order: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommitRequestBody {
+    offsets: Vec<OffsetBody>,
+    wc: WriteConcern,
+    wtimeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LeaseRequestBody {
+    #[serde(default = "default_topic")]
+    topic: String,
+    timeout_ms: Option<u64>,  // `LEASE_TIMEOUT_MS` by default
+    wc: WriteConcern,
+    wtimeout_ms: Option<u64>,
+}
+
+/// The write concern of an acknowledgement or a release of a leased message
+#[derive(Debug, Deserialize)]
+struct SettleRequestBody {
+    wc: WriteConcern,
+    wtimeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, Clone)]
+struct ResponseBody<T = WriteResult> {
     message: String,
-    status: bool
+    status: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    result: Option<T>,
+}
+
+impl ResponseBody {
+    fn rejected(message: String) -> Self {
+        Self { message, status: false, result: None }
+    }
+
+    fn done(message: String) -> Self {
+        Self { message, status: true, result: None }
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct WriteResult {
+    id: String,
+    partition: u32,
+    order: u32,
+    acked: Vec<String>,
+    elapsed_ms: u128,
+}
+
+#[derive(Serialize, Clone)]
+struct HeadResult {
+    head: u32,
+}
+
+#[derive(Serialize, Clone)]
+struct BatchResult {
+    partition: u32,
+    items: Vec<ItemResult>,
+    acked: Vec<String>,
+    elapsed_ms: u128,
+}
+
+/// A write result as remembered by its idempotency key, the single and the batch writes share one key namespace
+#[derive(Serialize, Clone)]
+#[serde(untagged)]
+enum WriteResponse {
+    Single(ResponseBody),
+    Batch(ResponseBody<BatchResult>),
+}
+
+impl From<ResponseBody> for WriteResponse {
+    fn from(body: ResponseBody) -> Self {
+        Self::Single(body)
+    }
+}
+
+impl From<ResponseBody<BatchResult>> for WriteResponse {
+    fn from(body: ResponseBody<BatchResult>) -> Self {
+        Self::Batch(body)
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct CommitResult {
+    acked: Vec<String>,
+    elapsed_ms: u128,
+}
+
+#[derive(Serialize, Clone)]
+struct LeaseResult {
+    #[serde(flatten)]
+    lease: Lease,
+    leased: Message,
+    acked: Vec<String>,
+    elapsed_ms: u128,
+}
+
+#[derive(Serialize, Clone)]
+struct ItemResult {
+    id: String,
+    order: u32,
+}
+
+#[derive(Deserialize)]
+struct TopicBody {
+    name: String,
+    partitions: Option<u32>,  // `TOPIC_PARTITIONS` by default
+}
+
+#[derive(Serialize)]
+struct TopicInfo {
+    name: String,
+    partitions: u32,
+    heads: Vec<u32>,  // the last ordering of every partition
+}
+
+#[derive(Serialize)]
+struct MetricsBody {
+    storage: StorageMetrics,
+    stash: Option<StorageMetrics>,
+    replication_compression: HashMap<String, Option<Codec>>,
+    replication_backlog: HashMap<String, usize>,
+    replication_progress: HashMap<String, u32>,
+}
+
+/// The hash of everything the write depends on, so a key reused with another request is detected,
+/// a batch differs from a single write of the same message
+fn fingerprint(append: &Append, batch: bool) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    batch.hash(&mut hasher);
+    (&append.topic, append.partition, &append.key, &append.producer_id, append.expected_last_order).hash(&mut hasher);
+    format!("{:?}", append.wc).hash(&mut hasher);
+    for draft in &append.messages {
+        (&draft.content, &draft.content_type, draft.ttl_ms).hash(&mut hasher);
+        draft.headers.iter().collect::<BTreeMap<_, _>>().hash(&mut hasher);
+    }
+
+    hasher.finish()
+}
+
+/// Claims the idempotency key of the request, if any, or responds with the result of the original write
+#[allow(clippy::result_large_err)]
+fn claim(
+    idempotency: &IdempotencyCache<WriteResponse>,
+    req: &HttpRequest,
+    idempotency_key: Option<&String>,
+    append: &Append,
+    batch: bool,
+) -> Result<Option<IdempotencyGuard<WriteResponse>>, HttpResponse> {
+    let key = req.headers()
+        .get("Idempotency-Key")
+        .and_then(|key| key.to_str().ok())
+        .map(String::from)
+        .or(idempotency_key.cloned());
+    // the same key may be used within different topics
+    match key.as_deref().map(|key| idempotency.claim(&format!("{}/{key}", append.topic), fingerprint(append, batch))) {
+        Some(Claim::Replay(status, body)) => {
+            log::info!("Replaying the result of the write with the idempotency key {key:?}");
+            Err(HttpResponse::build(status).json(body))
+        },
+        Some(Claim::InProgress) => Err(HttpResponse::Conflict().json(ResponseBody::rejected(
+            String::from("A write with the same idempotency key is still in progress. Please try later.")
+        ))),
+        Some(Claim::Mismatch) => Err(HttpResponse::UnprocessableEntity().json(ResponseBody::rejected(
+            String::from("The idempotency key has already been used with another request")
+        ))),
+        Some(Claim::New(guard)) => Ok(Some(guard)),
+        None => Ok(None),
+    }
+}
+
+fn reject(rejection: Rejection) -> HttpResponse {
+    let message = rejection.to_string();
+    match rejection {
+        Rejection::Empty
+        | Rejection::InvalidConcern(_)
+        | Rejection::InternalTopic(_)
+        | Rejection::AmbiguousPartition(_)
+        | Rejection::InvalidLeaseTimeout(_) => {
+            HttpResponse::BadRequest().json(ResponseBody::rejected(message))
+        }
+        Rejection::UnknownTopic(_) | Rejection::UnknownPartition(_) | Rejection::UnknownMessage(_) => {
+            HttpResponse::NotFound().json(ResponseBody::rejected(message))
+        }
+        Rejection::Redacted(_) => HttpResponse::Conflict().json(ResponseBody::rejected(message)),
+        Rejection::NoQuorum => HttpResponse::ServiceUnavailable().json(ResponseBody::rejected(message)),
+        Rejection::Backpressure(pressure) => {
+            let mut response = match pressure {
+                Backpressure::WindowFull { .. } => HttpResponse::ServiceUnavailable(),
+                Backpressure::QueueFull { .. } => HttpResponse::TooManyRequests(),
+            };
+            response
+                .insert_header(("Retry-After", BACKPRESSURE_RETRY_AFTER_S.to_string()))
+                .json(ResponseBody::rejected(message))
+        }
+        Rejection::Conflict(conflict) => HttpResponse::Conflict().json(
+            ResponseBody {
+                message,
+                status: false,
+                result: Some(HeadResult { head: conflict.head }),
+            }),
+        Rejection::OrderingExhausted(_) => HttpResponse::InsufficientStorage().json(ResponseBody::rejected(message)),
+    }
+}
+
+/// The response status and the message of a completed write
+fn delivery(acked: &Result<Vec<String>, WriteConcernError>, delivered: &str) -> (StatusCode, bool, String) {
+    match acked {
+        Ok(_) => (StatusCode::CREATED, true, String::from(delivered)),
+        Err(e @ WriteConcernError::Failed { .. }) => (StatusCode::SERVICE_UNAVAILABLE, false, e.to_string()),
+        Err(e @ WriteConcernError::TimedOut { .. }) => (StatusCode::GATEWAY_TIMEOUT, false, e.to_string()),
+    }
+}
+
+/// Appends a single message and replicates it, shared by the JSON and the raw writes
+async fn write_single(
+    writer: &Writer,
+    idempotency: &IdempotencyCache<WriteResponse>,
+    append: Append,
+    idempotency_key: Option<&String>,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let guard = match claim(idempotency, req, idempotency_key, &append, false) {
+        Ok(guard) => guard,
+        Err(response) => return response,
+    };
+    let prepared = match writer.prepare(&append).await {
+        Ok(prepared) => prepared,
+        Err(rejection) => return reject(rejection),
+    };
+
+    let (id, order) = prepared.items().remove(0);
+    let partition = prepared.partition();
+    if let Some(guard) = &guard {
+        // the message is in the log already, so an abandoned request must not be appended again on retry
+        guard.reserve(
+            StatusCode::ACCEPTED,
+            ResponseBody {
+                message: String::from("Message accepted, the replication is in progress"),
+                status: true,
+                result: Some(WriteResult { id: id.clone(), partition, order, acked: vec![], elapsed_ms: 0 }),
+            }.into());
+    }
+
+    let outcome = writer.replicate(prepared).await;
+    let (status, delivered, message) = delivery(&outcome.acked, "Message delivered");
+    let acked = outcome.acked.unwrap_or_else(|e| e.acked().to_vec());
+    let body = ResponseBody {
+        message,
+        status: delivered,
+        result: Some(WriteResult { id, partition, order, acked, elapsed_ms: outcome.elapsed_ms }),
+    };
+
+    // an unsatisfied write concern is not final, the message is in the log, so a retry gets the reserved `202`
+    if let Some(guard) = guard.filter(|_| delivered) {
+        guard.complete(status, body.clone().into());
+    }
+    HttpResponse::build(status).json(body)
 }
 
 #[post("/messages")]
 async fn write_message(
-    log: Data<MessageLog>,
-    replicator_client: Data<SharedReplicator>,
+    writer: Data<Writer>,
+    idempotency: Data<IdempotencyCache<WriteResponse>>,
+    topic: TopicLog,
     request: Json<RequestBody>,
     req: HttpRequest,
 ) -> HttpResponse {
     log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
 
-    if !replicator_client.verify_quorum().await {
-        return HttpResponse::ServiceUnavailable().json(
+    let append = Append { topic: topic.name.clone(), partition: topic.partition, ..Append::from(&*request) };
+    write_single(&writer, &idempotency, append, request.idempotency_key.as_ref(), &req).await
+}
+
+/// Appends the request body as is under its `Content-Type`, the `X-Header-*` request headers become the message headers
+#[post("/messages/raw")]
+async fn write_raw_message(
+    writer: Data<Writer>,
+    idempotency: Data<IdempotencyCache<WriteResponse>>,
+    topic: TopicLog,
+    query: Query<RawQuery>,
+    body: web::Bytes,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    let headers = req.headers()
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str().strip_prefix("x-header-")?, value.to_str().ok()?)))
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    let content_type = req.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .unwrap_or(BINARY_CONTENT_TYPE);
+    let append = Append {
+        topic: topic.name.clone(),
+        partition: topic.partition,
+        key: query.key.clone(),
+        messages: vec![Draft {
+            content: body.to_vec(),
+            headers,
+            content_type: Some(content_type.to_string()),
+            ttl_ms: query.ttl_ms,
+        }],
+        producer_id: query.producer_id.clone(),
+        wc: WriteConcern::parse(&query.wc),
+        wtimeout_ms: query.wtimeout_ms,
+        expected_last_order: query.expected_last_order,
+        ordering: None,
+        redacts: None,
+    };
+
+    write_single(&writer, &idempotency, append, query.idempotency_key.as_ref(), &req).await
+}
+
+/// Appends the messages under a contiguous ordering range and replicates them in one round,
+/// either all of them appear in the log or none do
+#[post("/messages/batch")]
+async fn write_batch(
+    writer: Data<Writer>,
+    idempotency: Data<IdempotencyCache<WriteResponse>>,
+    topic: TopicLog,
+    request: Json<BatchRequestBody>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    if request.messages.is_empty() {
+        return reject(Rejection::Empty);
+    }
+    let append = Append { topic: topic.name.clone(), partition: topic.partition, ..Append::from(&*request) };
+    let guard = match claim(&idempotency, &req, request.idempotency_key.as_ref(), &append, true) {
+        Ok(guard) => guard,
+        Err(response) => return response,
+    };
+    let prepared = match writer.prepare(&append).await {
+        Ok(prepared) => prepared,
+        Err(rejection) => return reject(rejection),
+    };
+
+    let items = prepared
+        .items()
+        .into_iter()
+        .map(|(id, order)| ItemResult { id, order })
+        .collect::<Vec<_>>();
+    let partition = prepared.partition();
+    if let Some(guard) = &guard {
+        guard.reserve(
+            StatusCode::ACCEPTED,
             ResponseBody {
-                message: String::from(
-                    "The service cannot save the message due to a temporary failure/absence of the required nodes. \
-                    Please try later."
-                ),
-                status: false,
-            });
+                message: String::from("Batch accepted, the replication is in progress"),
+                status: true,
+                result: Some(BatchResult { partition, items: items.clone(), acked: vec![], elapsed_ms: 0 }),
+            }.into());
     }
 
-    let request = request.into_inner();
-    let message = Message {
-        content: request.message.clone(),
+    let outcome = writer.replicate(prepared).await;
+    let (status, delivered, message) = delivery(&outcome.acked, "Batch delivered");
+    let acked = outcome.acked.unwrap_or_else(|e| e.acked().to_vec());
+    let body = ResponseBody {
+        message,
+        status: delivered,
+        result: Some(BatchResult { partition, items, acked, elapsed_ms: outcome.elapsed_ms }),
     };
 
-    log::info!("{:?} received", message);
+    // an unsatisfied write concern is not final, the message is in the log, so a retry gets the reserved `202`
+    if let Some(guard) = guard.filter(|_| delivered) {
+        guard.complete(status, body.clone().into());
+    }
+    HttpResponse::build(status).json(body)
+}
+
+#[get("/messages")]
+async fn list_messages(log: TopicLog, query: Query<ReadQuery>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    match query.read(&log).await {
+        Ok(page) => {
+            log::info!("Read {} messages, the head ordering is ({})", page.messages.len(), page.head);
+            HttpResponse::Ok().json(page)
+        }
+        Err(e) => HttpResponse::BadRequest().json(ResponseBody::rejected(e)),
+    }
+}
 
-    log.add(message.clone()).await;
-    replicator_client.replicate(message, request).await;
+#[get("/messages/stream")]
+async fn stream_messages(log: TopicLog, query: Query<StreamQuery>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
 
-    HttpResponse::Created().json(
-        ResponseBody {
-            status: true,
-            message: String::from("Message delivered")
+    // an ordering is reserved before the message is appended, so a later one can reach the log first
+    events::stream(&log, &query, &req, true).await
+}
+
+#[get("/messages/ws")]
+async fn subscribe_messages(topic: TopicLog, body: web::Payload, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    // the same contiguous tail as of the SSE stream
+    subscription::subscribe(&topic, &req, body, true)
+}
+
+#[get("/messages/at/{order}")]
+async fn get_message_at(log: TopicLog, at: Path<MessageAt>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    match log.get_at(at.order).await {
+        Some(message) => HttpResponse::Ok().json(message),
+        None => HttpResponse::NotFound().json(ResponseBody::rejected(format!("No message with ordering ({})", at.order))),
+    }
+}
+
+/// The payload as is under its original content type
+#[get("/messages/{id}/content")]
+async fn get_message_content(log: TopicLog, path: Path<MessageId>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    match log.get(&path.id).await {
+        Some(message) => HttpResponse::Ok().content_type(message.content_type).body(message.content),
+        None => HttpResponse::NotFound().json(ResponseBody::rejected(format!("No message with ID `{}`", path.id))),
+    }
+}
+
+#[get("/messages/{id}")]
+async fn get_message(log: TopicLog, path: Path<MessageId>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    match log.get(&path.id).await {
+        Some(message) => HttpResponse::Ok().json(message),
+        None => HttpResponse::NotFound().json(ResponseBody::rejected(format!("No message with ID `{}`", path.id))),
+    }
+}
+
+/// Appends a tombstone that makes every node drop the content of the message, its ordering slot is kept
+#[delete("/messages/{id}")]
+async fn redact_message(
+    writer: Data<Writer>,
+    topic: TopicLog,
+    path: Path<MessageId>,
+    query: Query<RedactQuery>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    let prepared = match writer
+        .redact(&topic.name, topic.partition, &path.id, WriteConcern::parse(&query.wc), query.wtimeout_ms)
+        .await
+    {
+        Ok(prepared) => prepared,
+        Err(rejection) => return reject(rejection),
+    };
+    let (id, order) = prepared.items().remove(0);
+    let partition = prepared.partition();
+
+    let outcome = writer.replicate(prepared).await;
+    let (status, delivered, message) = delivery(&outcome.acked, "Message redacted");
+    let acked = outcome.acked.unwrap_or_else(|e| e.acked().to_vec());
+
+    HttpResponse::build(status).json(ResponseBody {
+        message,
+        status: delivered,
+        result: Some(WriteResult { id, partition, order, acked, elapsed_ms: outcome.elapsed_ms }),
+    })
+}
+
+#[get("/anti-entropy")]
+async fn anti_entropy_reports(
+    topic: TopicLog,
+    replicator_client: Data<SharedReplicator>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    let partition = topic.partition.unwrap_or_default();
+    match replicator_client.topic(&topic.name).await.and_then(|topic| topic.partitions.get(partition as usize).cloned()) {
+        Some(partition) => HttpResponse::Ok().json(&*partition.reports.lock().await),
+        None => HttpResponse::NotFound().json(ResponseBody::rejected(format!("No topic `{}`", topic.name))),
+    }
+}
+
+#[get("/metrics")]
+async fn metrics(
+    log: TopicLog,
+    replicator_client: Data<SharedReplicator>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    HttpResponse::Ok().json(
+        MetricsBody {
+            storage: log.metrics().await,
+            stash: replicator_client.stash_metrics(&log.name, log.partition.unwrap_or_default()).await,
+            replication_compression: replicator_client.compression().await,
+            replication_backlog: replicator_client.backlog(&log.name, log.partition.unwrap_or_default()).await,
+            replication_progress: replicator_client.progress(&log.name, log.partition.unwrap_or_default()).await,
         })
 }
 
-#[get("/messages")]
-async fn list_messages(log: Data<MessageLog>, req: HttpRequest) -> HttpResponse {
+#[post("/topics")]
+async fn create_topic(
+    replicator_client: Data<SharedReplicator>,
+    request: Json<TopicBody>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    if let Err(e) = validate_name("topic", &request.name) {
+        return HttpResponse::BadRequest().json(ResponseBody::rejected(e))
+    }
+    if is_internal(&request.name) {
+        return reject(Rejection::InternalTopic(request.name.clone()))
+    }
+    let partitions = request.partitions.unwrap_or(*TOPIC_PARTITIONS);
+    if !(1..=MAX_PARTITIONS).contains(&partitions) {
+        return HttpResponse::BadRequest().json(ResponseBody::rejected(
+            format!("Invalid partition count ({partitions}), expected from 1 to {MAX_PARTITIONS}")
+        ))
+    }
+    match replicator_client.create_topic(&request.name, partitions).await {
+        true => HttpResponse::Created().json(
+            TopicInfo { name: request.name.clone(), partitions, heads: vec![0; partitions as usize] }
+        ),
+        false => HttpResponse::Conflict().json(ResponseBody::rejected(format!("The topic `{}` exists already", request.name))),
+    }
+}
+
+#[get("/topics")]
+async fn list_topics(logs: Data<TopicLogs>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    let mut topics = vec![];
+    for name in logs.names() {
+        if let Some(partitions) = logs.partitions(&name) {
+            let mut heads = Vec::with_capacity(partitions.len());
+            for log in &partitions {
+                heads.push(log.head().await);
+            }
+            topics.push(TopicInfo { name, partitions: partitions.len() as u32, heads });
+        }
+    }
+
+    HttpResponse::Ok().json(topics)
+}
+
+/// Drops the topic with its messages on every node
+#[delete("")]
+async fn delete_topic(topic: TopicLog, replicator_client: Data<SharedReplicator>, req: HttpRequest) -> HttpResponse {
     log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
 
-    let messages = log.get_all().await;
-    log::info!("Log has {} messages", messages.len());
+    if topic.name == DEFAULT_TOPIC {
+        return HttpResponse::BadRequest().json(ResponseBody::rejected(String::from("The default topic cannot be deleted")))
+    }
+    if is_internal(&topic.name) {
+        return reject(Rejection::InternalTopic(topic.name.clone()))
+    }
+    match replicator_client.delete_topic(&topic.name).await {
+        true => HttpResponse::Ok().json(ResponseBody::done(format!("The topic `{}` deleted", topic.name))),
+        false => HttpResponse::NotFound().json(ResponseBody::rejected(format!("No topic `{}`", topic.name))),
+    }
+}
+
+/// Commits the offsets of the group atomically, they are replicated to every node as messages of the offsets topic
+#[post("/groups/{group}/offsets")]
+async fn commit_offsets(
+    writer: Data<Writer>,
+    logs: Data<TopicLogs>,
+    path: Path<GroupPath>,
+    request: Json<CommitRequestBody>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    if let Err(e) = validate_name("group", &path.group) {
+        return HttpResponse::BadRequest().json(ResponseBody::rejected(e))
+    }
+    let mut offsets = Vec::with_capacity(request.offsets.len());
+    for offset in &request.offsets {
+        let head = match logs.get(&offset.topic, offset.partition) {
+            Some(log) => log.head().await,
+            None => return HttpResponse::NotFound().json(ResponseBody::rejected(
+                format!("No partition ({}) of topic `{}`", offset.partition, offset.topic)
+            )),
+        };
+        if offset.order > head {
+            return HttpResponse::BadRequest().json(ResponseBody::rejected(format!(
+                "The offset ({}) is beyond the last ordering ({head}) of `{}/{}`", offset.order, offset.topic, offset.partition
+            )))
+        }
+        offsets.push(OffsetCommit {
+            group: path.group.clone(),
+            topic: offset.topic.clone(),
+            partition: offset.partition,
+            order: offset.order,
+        });
+    }
+
+    let outcome = match writer.commit(offsets, request.wc.clone(), request.wtimeout_ms).await {
+        Ok(outcome) => outcome,
+        Err(rejection) => return reject(rejection),
+    };
+    let (status, delivered, message) = delivery(&outcome.acked, "Offsets committed");
+    let acked = outcome.acked.unwrap_or_else(|e| e.acked().to_vec());
+
+    HttpResponse::build(status).json(ResponseBody {
+        message,
+        status: delivered,
+        result: Some(CommitResult { acked, elapsed_ms: outcome.elapsed_ms }),
+    })
+}
+
+#[get("/groups")]
+async fn list_groups(groups: Data<ConsumerGroups>, logs: Data<TopicLogs>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    let mut reports = vec![];
+    for group in groups.names() {
+        reports.extend(groups.lag(&group, &logs).await);
+    }
+
+    HttpResponse::Ok().json(reports)
+}
+
+#[get("/groups/{group}/offsets")]
+async fn get_group_offsets(
+    groups: Data<ConsumerGroups>,
+    logs: Data<TopicLogs>,
+    path: Path<GroupPath>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    match groups.lag(&path.group, &logs).await {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => HttpResponse::NotFound().json(ResponseBody::rejected(format!("No group `{}`", path.group))),
+    }
+}
+
+#[get("/groups/{group}/offsets/{topic}/{partition}")]
+async fn get_offset(
+    groups: Data<ConsumerGroups>,
+    logs: Data<TopicLogs>,
+    path: Path<OffsetPath>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    match groups.offset(&path.group, &path.topic, path.partition, &logs).await {
+        Some(offset) => HttpResponse::Ok().json(offset),
+        None => HttpResponse::NotFound().json(ResponseBody::rejected(format!(
+            "No offset of group `{}` at `{}/{}`", path.group, path.topic, path.partition
+        ))),
+    }
+}
+
+/// Leases the next message of the topic the group has neither acknowledged nor leased, `204 No Content` if there is none.
+/// The message is invisible to the other consumers of the group until the lease expires
+#[post("/groups/{group}/leases")]
+async fn lease_message(
+    writer: Data<Writer>,
+    path: Path<GroupPath>,
+    request: Json<LeaseRequestBody>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    if let Err(e) = validate_name("group", &path.group) {
+        return HttpResponse::BadRequest().json(ResponseBody::rejected(e))
+    }
+    let leased = writer
+        .lease(&path.group, &request.topic, request.timeout_ms, request.wc.clone(), request.wtimeout_ms)
+        .await;
+    let (lease, message, outcome) = match leased {
+        Ok(Some(leased)) => leased,
+        Ok(None) => return HttpResponse::NoContent().finish(),
+        Err(rejection) => return reject(rejection),
+    };
+    log::info!("master: message ({}) of `{}/{}` leased to group `{}`", lease.order, lease.topic, lease.partition, path.group);
+    let (status, delivered, response_message) = delivery(&outcome.acked, "Message leased");
+    let acked = outcome.acked.unwrap_or_else(|e| e.acked().to_vec());
+
+    HttpResponse::build(status).json(ResponseBody {
+        message: response_message,
+        status: delivered,
+        result: Some(LeaseResult { lease, leased: message, acked, elapsed_ms: outcome.elapsed_ms }),
+    })
+}
+
+/// Acknowledges or releases the leased message, shared by the ack and the nack
+async fn settle(writer: &Writer, path: &LeasePath, request: &SettleRequestBody, ack: bool) -> HttpResponse {
+    let settled = writer
+        .settle(&path.group, &path.lease_id, ack, request.wc.clone(), request.wtimeout_ms)
+        .await;
+    let outcome = match settled {
+        Ok(Some(outcome)) => outcome,
+        Ok(None) => return HttpResponse::NotFound().json(ResponseBody::rejected(format!(
+            "No lease `{}` of group `{}`", path.lease_id, path.group
+        ))),
+        Err(rejection) => return reject(rejection),
+    };
+    let (status, delivered, message) = delivery(&outcome.acked, if ack { "Message acknowledged" } else { "Message released" });
+    let acked = outcome.acked.unwrap_or_else(|e| e.acked().to_vec());
+
+    HttpResponse::build(status).json(ResponseBody {
+        message,
+        status: delivered,
+        result: Some(CommitResult { acked, elapsed_ms: outcome.elapsed_ms }),
+    })
+}
+
+/// The message is never delivered to the group again, an expired lease can be acknowledged until the message
+/// is leased again
+#[post("/groups/{group}/leases/{lease_id}/ack")]
+async fn ack_message(
+    writer: Data<Writer>,
+    path: Path<LeasePath>,
+    request: Json<SettleRequestBody>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    settle(&writer, &path, &request, true).await
+}
+
+/// Releases the message for the redelivery without waiting for the lease to expire
+#[post("/groups/{group}/leases/{lease_id}/nack")]
+async fn nack_message(
+    writer: Data<Writer>,
+    path: Path<LeasePath>,
+    request: Json<SettleRequestBody>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    settle(&writer, &path, &request, false).await
+}
+
+#[get("/groups/{group}/leases")]
+async fn list_leases(queues: Data<WorkQueues>, path: Path<GroupPath>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    HttpResponse::Ok().json(queues.leases(&path.group))
+}
 
-    HttpResponse::Ok().json(messages)
+/// The message resources of a topic partition, served for the default topic without the `/topics/{topic}` prefix
+/// and for the first partition, or the one of the key for a write, without the `/partitions/{partition}` one
+fn messages(scope: Scope) -> Scope {
+    scope
+        .service(write_message)
+        .service(write_raw_message)
+        .service(write_batch)
+        .service(list_messages)
+        .service(stream_messages)
+        .service(subscribe_messages)
+        .service(get_message_at)
+        .service(get_message_content)
+        .service(get_message)
+        .service(redact_message)
+        .service(anti_entropy_reports)
+        .service(metrics)
 }
 
 pub fn config(config: &mut web::ServiceConfig) {
+    // registered from the most specific one, so a shorter scope does not take over the longer paths
+    config.service(messages(web::scope("/api/v1/topics/{topic}/partitions/{partition}")));
+    config.service(messages(web::scope("/api/v1/topics/{topic}")).service(delete_topic));
     config.service(
-        web::scope("/api/v1")
-            .service(write_message)
-            .service(list_messages),
+        messages(web::scope("/api/v1"))
+            .service(create_topic)
+            .service(list_topics)
+            .service(commit_offsets)
+            .service(list_groups)
+            .service(get_group_offsets)
+            .service(get_offset)
+            .service(lease_message)
+            .service(ack_message)
+            .service(nack_message)
+            .service(list_leases),
     );
 }
 
@@ -100,20 +965,30 @@ async fn main() {
     let rep_client = Arc::new(ReplicatorMultiClient::init());
     let cb = CircuitBreaker::new(rep_client.clone());
 
-    tokio::spawn(JoinListener::start(cb));
+    let groups = ConsumerGroups::default();
+    let queues = WorkQueues::default();
+    let writer = Writer::new(rep_client.clone(), groups.clone(), queues.clone());
 
-    let log = MessageLog::new();
-    log::debug!("Initialized MessageLog object");
+    tokio::spawn(rep_client.clone().sweep());
+    tokio::spawn(JoinListener::start(cb, rep_client.clone(), ClientApi::new(writer.clone())));
 
-    let app_log = Data::new(log);
+    let logs = Data::new(rep_client.logs());
+    let groups = Data::new(groups);
+    let queues = Data::new(queues);
+    let writer = Data::new(writer);
 
     let replicator_client = Data::new(rep_client);
+    let idempotency = Data::new(IdempotencyCache::<WriteResponse>::new());
 
     log::info!("Starting HTTP server");
     HttpServer::new(move || {
         App::new()
-            .app_data(app_log.clone())
+            .app_data(logs.clone())
+            .app_data(groups.clone())
+            .app_data(queues.clone())
+            .app_data(writer.clone())
             .app_data(replicator_client.clone())
+            .app_data(idempotency.clone())
             .configure(config)
             .default_service(web::route().to(|| HttpResponse::MethodNotAllowed()))
     })
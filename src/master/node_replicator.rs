@@ -0,0 +1,435 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use std::env;
+
+use lazy_static::lazy_static;
+use tokio::select;
+use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, sleep, Instant, Interval, MissedTickBehavior};
+use tonic::Request;
+use tonic::transport::Channel;
+
+use replog::common::compression::Codec;
+use replog::common::retry::Attempts;
+use crate::anti_entropy::{Reconciler, ANTI_ENTROPY_INTERVAL_MS};
+use crate::circuit_breaker::sync_request::SyncClaim;
+use crate::circuit_breaker::sync_request::sync_request_client::SyncRequestClient;
+use crate::replicator_client::replicator::{Ack, AckStatus, Replica, ReplicaBatch};
+use crate::replicator_client::replicator::replicator_client::ReplicatorClient;
+use crate::topics::{Partition, Stashed};
+use crate::write_concern::Labels;
+
+lazy_static! {
+    static ref PROBE_INTERVAL_MS: Duration = Duration::from_millis(
+        env::var("PROBE_INTERVAL_MS")
+            .unwrap_or_default()
+            .parse()
+            .unwrap_or(30000)
+    );
+}
+
+pub type Stash = Arc<Mutex<BTreeMap<u32, Stashed>>>;
+
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ActorState {
+    CatchingUp,
+    Replicating,
+    Retrying,
+    Probing,
+    Reconciling,
+}
+
+/// What a node has declared about itself on join
+#[derive(Debug, Clone)]
+pub struct NodeInfo {
+    pub orderings: HashMap<(String, u32), u32>,  // by topic and partition
+    pub codec: Option<Codec>,
+    pub labels: Labels,
+}
+
+/// A single replica or a contiguous batch of them, delivered in one request
+struct Job {
+    replicas: Vec<Replica>,
+    ack: oneshot::Sender<bool>,
+}
+
+struct Pending {
+    replicas: Vec<Replica>,
+    waiters: Vec<oneshot::Sender<bool>>,
+}
+
+impl Pending {
+    fn last(&self) -> u32 {
+        self.replicas.last().map(|replica| replica.order).unwrap_or_default()
+    }
+}
+
+/// A handle to the replication actor of a single topic partition on a single secondary node.
+/// Dropping the handle stops the actor.
+#[derive(Debug)]
+pub struct NodeHandle {
+    jobs: mpsc::UnboundedSender<Job>,
+    task: JoinHandle<()>,
+    backlog: Arc<AtomicUsize>,
+    progress: Arc<AtomicU32>,
+}
+
+impl NodeHandle {
+
+    /// The current ordering of the node, as reported by its last acknowledgement
+    pub fn progress(&self) -> u32 {
+        self.progress.load(Ordering::Acquire)
+    }
+
+    /// Number of messages submitted to the node and not acknowledged yet
+    pub fn backlog(&self) -> usize {
+        self.backlog.load(Ordering::Acquire)
+    }
+
+    /// Reserves room for `n` messages in the in-flight window in a single atomic step, so concurrent writes
+    /// never overrun it, the backlog is returned if the window is full
+    pub fn reserve(&self, n: usize, window: usize) -> Result<Arc<AtomicUsize>, usize> {
+        self.backlog
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |backlog| {
+                (backlog + n <= window).then_some(backlog + n)
+            })
+            .map(|_| self.backlog.clone())
+    }
+
+    /// Submits contiguous replicas to be delivered in one request, their room has been reserved already
+    pub fn submit(&self, replicas: Vec<Replica>) -> oneshot::Receiver<bool> {
+        let (ack, rx) = oneshot::channel();
+        // a closed actor drops the job together with its sender, so the receiver resolves anyway
+        let _ = self.jobs.send(Job { replicas, ack });
+
+        rx
+    }
+}
+
+impl Drop for NodeHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+
+/// Owns the connection to a secondary node and delivers the replicas of a partition strictly in its ordering sequence:
+/// the lowest pending ordering is always sent first, and the next one waits until it is acknowledged.
+pub struct NodeReplicator {
+    host: String,
+    // `host/topic/partition`, for the logs
+    stream: String,
+    client: ReplicatorClient<Channel>,
+    sync_client: SyncRequestClient<Channel>,
+    partition: Arc<Partition>,
+    jobs: mpsc::UnboundedReceiver<Job>,
+    queue: BTreeMap<u32, Pending>,
+    backlog: Arc<AtomicUsize>,
+    progress: Arc<AtomicU32>,
+    state: ActorState,
+    attempts: Attempts,
+    anti_entropy: Interval,
+    acked: u32,
+    // the stashed replicas after `acked` that fail to decompress, they are never delivered, so the node is not lagging behind them
+    unreadable: BTreeSet<u32>,
+}
+
+impl NodeReplicator {
+
+    pub fn spawn(host: &str, channel: &Channel, info: &NodeInfo, partition: Arc<Partition>) -> NodeHandle {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let ordering = info.orderings
+            .get(&(partition.topic.clone(), partition.index))
+            .copied()
+            .unwrap_or_default();
+        let backlog = Arc::new(AtomicUsize::new(0));
+        let progress = Arc::new(AtomicU32::new(ordering));
+        let mut anti_entropy = interval_at(
+            Instant::now() + *ANTI_ENTROPY_INTERVAL_MS,
+            *ANTI_ENTROPY_INTERVAL_MS
+        );
+        anti_entropy.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut client = ReplicatorClient::new(channel.clone());
+        let mut sync_client = SyncRequestClient::new(channel.clone());
+        if let Some(codec) = info.codec {
+            client = client
+                .send_compressed(codec.encoding())
+                .accept_compressed(codec.encoding());
+            sync_client = sync_client
+                .send_compressed(codec.encoding())
+                .accept_compressed(codec.encoding());
+        }
+
+        let actor = Self {
+            host: host.to_string(),
+            stream: format!("{host}/{partition}"),
+            client,
+            sync_client,
+            partition,
+            jobs: rx,
+            queue: BTreeMap::new(),
+            backlog: backlog.clone(),
+            progress: progress.clone(),
+            state: ActorState::CatchingUp,
+            attempts: Attempts::default(),
+            anti_entropy,
+            acked: ordering,
+            unreadable: BTreeSet::new(),
+        };
+
+        NodeHandle { jobs: tx, task: tokio::spawn(actor.run()), backlog, progress }
+    }
+
+    async fn run(mut self) {
+        log::info!("{}: replication actor started from ordering ({})", self.stream, self.acked);
+        loop {
+            if self.state == ActorState::CatchingUp {
+                self.catch_up().await;
+            }
+
+            if self.queue.is_empty() && self.lagging().await {
+                // the writes that have not been admitted to the full window are delivered from the stash
+                self.state = ActorState::CatchingUp;
+                continue
+            }
+            if self.queue.is_empty() {
+                // the anti-entropy pass runs only on an idle node, so it never races with the delivery
+                select! {
+                    job = self.jobs.recv() => match job {
+                        Some(job) => self.enqueue(job),
+                        None => break,
+                    },
+                    _ = self.anti_entropy.tick() => {
+                        self.reconcile().await;
+                        continue
+                    }
+                }
+            }
+            while let Ok(job) = self.jobs.try_recv() {
+                self.enqueue(job);
+            }
+
+            match self.state {
+                ActorState::Probing => self.probe().await,
+                _ => self.replicate_next().await,
+            }
+        }
+        log::info!("{}: replication actor stopped", self.stream);
+    }
+
+    fn enqueue(&mut self, job: Job) {
+        let (first, last) = match (job.replicas.first(), job.replicas.last()) {
+            (Some(first), Some(last)) => (first.order, last.order),
+            _ => return,
+        };
+        // the replicas already queued by the catch-up have been counted once, so they are merged into the job
+        let queued = self.queue.range(first..=last).map(|(order, _)| *order).collect::<Vec<_>>();
+        let mut waiters = vec![];
+        for order in queued {
+            if let Some(pending) = self.queue.remove(&order) {
+                self.backlog.fetch_sub(pending.replicas.len(), Ordering::AcqRel);
+                waiters.extend(pending.waiters);
+            }
+        }
+
+        if self.state == ActorState::Probing {
+            // the node is unreachable, so the write should not wait for it;
+            // the replicas are still queued and will be delivered once the node responds again
+            let _ = job.ack.send(false);
+        } else {
+            waiters.push(job.ack);
+        }
+        self.queue.insert(first, Pending { replicas: job.replicas, waiters });
+    }
+
+    fn queued(&self, order: u32) -> bool {
+        self.queue
+            .range(..=order)
+            .next_back()
+            .is_some_and(|(_, pending)| pending.last() >= order)
+    }
+
+    /// Whether the stash has readable messages after the last acknowledged one that have never been queued for the node
+    async fn lagging(&self) -> bool {
+        self.partition.stash
+            .lock()
+            .await
+            .range(self.acked + 1..)
+            .any(|(order, _)| !self.unreadable.contains(order))
+    }
+
+    async fn catch_up(&mut self) {
+        self.unreadable = self.unreadable.split_off(&(self.acked + 1));
+        let stash = self.partition.stash.lock().await;
+        let mut missing = 0;
+        // a batch is replayed in a single request, so the node never applies a part of it
+        let mut batch: Vec<Replica> = vec![];
+        for (order, stashed) in stash.range(self.acked + 1..) {
+            if self.queued(*order) {
+                continue
+            }
+            // a corrupted replica is skipped rather than delivered with a wrong content
+            let Ok(replica) = stashed.load() else {
+                if self.unreadable.insert(*order) {
+                    log::error!("{}: the stashed message ({order}) is unreadable and cannot be delivered", self.stream);
+                }
+                continue
+            };
+            if batch.last().is_some_and(|last| !last.batched_with(&replica)) {
+                missing += batch.len();
+                self.queue.insert(batch[0].order, Pending { replicas: std::mem::take(&mut batch), waiters: vec![] });
+            }
+            batch.push(replica);
+        }
+        if !batch.is_empty() {
+            missing += batch.len();
+            self.queue.insert(batch[0].order, Pending { replicas: batch, waiters: vec![] });
+        }
+        if missing > 0 {
+            self.backlog.fetch_add(missing, Ordering::AcqRel);
+            log::info!("{}: catching up {missing} message(s) after ordering ({})", self.stream, self.acked);
+        }
+
+        self.state = ActorState::Replicating;
+    }
+
+    async fn replicate_next(&mut self) {
+        let replicas = match self.queue.first_key_value() {
+            Some((_, pending)) => pending.replicas.clone(),
+            None => return,
+        };
+
+        if let Some(ack) = self.send(replicas).await {
+            if self.acknowledge(ack).await { return }
+        }
+
+        if self.attempts.next() {
+            self.state = ActorState::Retrying;
+            log::error!(
+                "{}: request failed, retrying after {} ms, {} attempts left...",
+                self.stream,
+                self.attempts.backoff_ms.as_millis(),
+                self.attempts.n);
+
+            self.attempts.delay().await
+        } else {
+            log::error!("{}: message completely failed to replicate \
+            ({} message(s) are kept queued and will be probed every {} ms)",
+                self.stream, self.queue.len(), PROBE_INTERVAL_MS.as_millis());
+
+            for pending in self.queue.values_mut() {
+                pending.waiters.drain(..).for_each(|ack| { let _ = ack.send(false); });
+            }
+            self.state = ActorState::Probing;
+        }
+    }
+
+    async fn probe(&mut self) {
+        sleep(*PROBE_INTERVAL_MS).await;
+
+        let (order, replicas) = match self.queue.first_key_value() {
+            Some((order, pending)) => (*order, pending.replicas.clone()),
+            None => return,
+        };
+
+        log::info!("{}: probing the node with ordering ({order})...", self.stream);
+        if let Some(ack) = self.send(replicas).await {
+            if self.acknowledge(ack).await {
+                log::info!("{}: node responded, resuming the replication", self.stream);
+            }
+        }
+    }
+
+    /// Completes the head message according to the node status, returns `false` if it has to be resent
+    async fn acknowledge(&mut self, ack: Ack) -> bool {
+        self.progress.store(ack.ordering, Ordering::Release);
+
+        match ack.status() {
+            AckStatus::RejectedEpoch => {
+                log::warn!("{}: node is bound to another master epoch, requesting it to rejoin...", self.stream);
+                if let Err(e) = self.sync_client.sync(Request::new(SyncClaim {})).await {
+                    log::error!("{}: SyncRequest failed - {e:?}", self.stream);
+                }
+                return false
+            }
+            AckStatus::Buffered => {
+                let head = self.queue.first_key_value().map(|(order, _)| *order).unwrap_or_default();
+                let fillable = ack.ordering + 1 < head
+                    && self.partition.stash.lock().await.range(ack.ordering + 1..head).next().is_some();
+                if !fillable {
+                    // nothing to replay before the head, the node completes the order correction on its own
+                    self.complete_head(false);
+                } else {
+                    // the head stays queued with its waiters, the catch-up puts the missing messages before it,
+                    // and the head is resent right after them
+                    log::warn!("{}: node is behind at ordering ({}), catching up...", self.stream, ack.ordering);
+                    self.acked = ack.ordering;
+                    self.state = ActorState::CatchingUp;
+                }
+            }
+            AckStatus::RejectedStale => self.complete_head(false),
+            AckStatus::Applied | AckStatus::Duplicate => self.complete_head(true),
+            AckStatus::Unknown => self.complete_head(ack.success),
+        }
+
+        self.attempts = Attempts::default();
+        if self.state != ActorState::CatchingUp {
+            self.state = ActorState::Replicating;
+        }
+        true
+    }
+
+    async fn reconcile(&mut self) {
+        self.state = ActorState::Reconciling;
+        match Reconciler::new(&self.stream, &mut self.client, &self.partition).run(self.acked).await {
+            Ok(report) => {
+                match report.repaired.len() {
+                    0 => log::info!("{}: anti-entropy pass found no divergence up to ({})", self.stream, self.acked),
+                    n => log::warn!("{}: anti-entropy pass repaired {n} range(s) - {:?}", self.stream, report.repaired),
+                }
+                self.partition.reports.lock().await.insert(self.host.clone(), report);
+            }
+            Err(e) => log::error!("{}: anti-entropy pass failed - {e:?}", self.stream),
+        }
+        self.state = ActorState::Replicating;
+    }
+
+    async fn send(&mut self, mut replicas: Vec<Replica>) -> Option<Ack> {
+        let order = replicas.first().map(|replica| replica.order).unwrap_or_default();
+        let response = match replicas.len() {
+            1 => {
+                log::info!("{}: message with ordering ({order}) sent for replication", self.stream);
+                self.client.replicate(Request::new(replicas.remove(0))).await
+            }
+            n => {
+                log::info!("{}: batch of {n} messages from ordering ({order}) sent for replication", self.stream);
+                self.client.replicate_batch(Request::new(ReplicaBatch { replicas })).await
+            }
+        };
+
+        match response {
+            Ok(body) => {
+                let ack = body.into_inner();
+                log::info!("{}: message ({order}) - replication status - {:?}", self.stream, ack.status());
+                Some(ack)
+            }
+            Err(e) => {
+                log::error!("{}: message ({order}) - replication failed - {e:?}", self.stream);
+                None
+            }
+        }
+    }
+
+    fn complete_head(&mut self, applied: bool) {
+        if let Some((_, pending)) = self.queue.pop_first() {
+            self.acked = pending.last();
+            self.backlog.fetch_sub(pending.replicas.len(), Ordering::AcqRel);
+            pending.waiters.into_iter().for_each(|ack| { let _ = ack.send(applied); });
+        }
+    }
+}
@@ -1,72 +1,314 @@
-use std::cmp::{max, min};
-use std::collections::{HashMap, HashSet};
-use std::hash::{Hash, Hasher};
-use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
+use std::env;
+use std::fmt;
 
-use log;
+use lazy_static::lazy_static;
 use uuid::Uuid;
 use futures::StreamExt;
+use futures::future::join_all;
 use futures::stream::FuturesUnordered;
-use tokio::sync::{Barrier, Mutex};
-use tokio::task::JoinHandle;
+use tokio::sync::Mutex;
 use tokio::time::timeout;
-use tonic::Request;
 use tonic::transport::Channel;
-use async_channel as ac;
+use tonic::{Request, Response, Status};
 
-use replicator::Replica;
+use replicator::{Ack, Replica, TopicName};
 use replicator::replicator_client::ReplicatorClient;
-use replog::WRITE_QUORUM;
-use replog::common::retry::Attempts;
-use crate::{RequestBody, Message};
+use replog::{NODE_LABELS, WRITE_QUORUM};
+use replog::common::compression::Codec;
+use replog::common::message::{Message, StorageMetrics};
+use replog::common::groups::OFFSETS_TOPIC;
+use replog::common::queues::LEASES_TOPIC;
+use replog::common::topics::{sweeper, TopicLogs, DEFAULT_TOPIC};
+use crate::node_replicator::{NodeHandle, NodeInfo, NodeReplicator};
+use crate::topics::{Partition, Topic, TOPIC_PARTITIONS};
+use crate::write_concern::{Labels, Requirement, WriteConcern};
 
 pub mod replicator {
     tonic::include_proto!("replica");
 }
 
-static GLOBAL_ORDERING: AtomicU32 = AtomicU32::new(1);
+impl From<&Replica> for Message {
+    fn from(replica: &Replica) -> Self {
+        Self {
+            id: replica.id.clone(),
+            order: replica.order,
+            content: replica.content.clone(),
+            headers: replica.headers.clone(),
+            content_type: replica.content_type.clone(),
+            timestamp: replica.timestamp,
+            producer_id: (!replica.producer_id.is_empty()).then(|| replica.producer_id.clone()),
+            key: (!replica.key.is_empty()).then(|| replica.key.clone()),
+            redacts: (!replica.redacts.is_empty()).then(|| replica.redacts.clone()),
+            redacted: replica.redacted,
+            ttl_ms: (replica.ttl_ms > 0).then_some(replica.ttl_ms),
+        }
+    }
+}
 
-impl Hash for Replica {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.id.hash(state);
-        self.order.hash(state);
-        self.content.hash(state);
+lazy_static! {
+    static ref NODE_INFLIGHT_WINDOW: usize = env::var("NODE_INFLIGHT_WINDOW")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(64);
+    static ref REPLICATION_QUEUE_LIMIT: usize = env::var("REPLICATION_QUEUE_LIMIT")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(1024);
+    /// Identifies this master run, so the nodes can tell the messages of a previous one apart
+    pub static ref EPOCH: String = Uuid::new_v4().to_string();
+    pub static ref WTIMEOUT_MS: u64 = env::var("WTIMEOUT_MS")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(60000);
+    pub static ref BACKPRESSURE_RETRY_AFTER_S: u64 = env::var("BACKPRESSURE_RETRY_AFTER_S")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(5);
+}
+
+#[derive(Debug)]
+pub enum Backpressure {
+    WindowFull { host: String, backlog: usize },
+    QueueFull { queued: usize },
+}
+
+impl fmt::Display for Backpressure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WindowFull { host, backlog } => write!(
+                f, "The node `{host}` is lagging behind with {backlog} unacknowledged messages. Please try later."
+            ),
+            Self::QueueFull { queued } => write!(
+                f, "The replication queue is full ({queued} messages in flight). Please slow down."
+            ),
+        }
     }
 }
 
-impl Eq for Replica {}
+#[derive(Debug)]
+pub enum WriteConcernError {
+    Failed { required: Requirement, acked: Vec<String> },
+    TimedOut { required: Requirement, acked: Vec<String>, wtimeout: Duration },
+}
 
-impl From<&Message> for Replica {
-    fn from(msg: &Message) -> Self {
-        Self {
-            id: Uuid::new_v4().to_string(),
-            order: GLOBAL_ORDERING.fetch_add(1, Ordering::SeqCst),
-            content: msg.content.clone(),
+impl WriteConcernError {
+    pub fn acked(&self) -> &[String] {
+        match self {
+            Self::Failed { acked, .. } | Self::TimedOut { acked, .. } => acked,
+        }
+    }
+}
+
+impl fmt::Display for WriteConcernError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Failed { required, acked } => write!(
+                f, "The write concern ({required}) is not satisfied, the message is acknowledged by the master and {} node(s) {acked:?} only. \
+                It stays stashed and will be delivered to the rest of the nodes later.",
+                acked.len()
+            ),
+            Self::TimedOut { required, acked, wtimeout } => write!(
+                f, "The write concern ({required}) is not satisfied within {} ms, {} acknowledgement(s) achieved \
+                (the master and {acked:?}). The replication continues in the background.",
+                wtimeout.as_millis(), acked.len() + 1
+            ),
+        }
+    }
+}
+
+/// The head ordering of the master differs from the one expected by a conditional write
+#[derive(Debug)]
+pub struct OrderingConflict {
+    pub head: u32,
+}
+
+impl fmt::Display for OrderingConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The last message ordering is ({}), the write is not applied", self.head)
+    }
+}
+
+/// The room reserved for a write in the in-flight windows of the nodes it has been admitted to,
+/// the reservations that are not used by the replication are released on drop
+#[derive(Debug)]
+pub struct Admission {
+    n: usize,
+    nodes: HashMap<String, Arc<AtomicUsize>>,
+}
+
+impl Admission {
+    fn take(&mut self, host: &str) -> bool {
+        self.nodes.remove(host).is_some()
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        for backlog in self.nodes.values() {
+            backlog.fetch_sub(self.n, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Sends a topic change to every node, a node that has missed it catches up on its next join
+async fn broadcast<F, R>(clients: Vec<(String, ReplicatorClient<Channel>)>, call: F, topic: TopicName)
+where
+    F: Fn(ReplicatorClient<Channel>, Request<TopicName>) -> R,
+    R: Future<Output = Result<Response<Ack>, Status>>,
+{
+    let calls = clients.into_iter().map(|(host, client)| {
+        let response = call(client, Request::new(topic.clone()));
+        let name = &topic.name;
+        async move {
+            if let Err(e) = response.await {
+                log::error!("{host}: topic `{name}` change failed - {e:?}");
+            }
+        }
+    });
+
+    join_all(calls).await;
+}
+
+/// A joined node and its replication actor per topic partition
+#[derive(Debug)]
+struct Node {
+    info: NodeInfo,
+    channel: Channel,
+    streams: HashMap<String, Vec<NodeHandle>>,
+}
+
+impl Node {
+    fn backlog(&self) -> usize {
+        self.streams.values().flatten().map(NodeHandle::backlog).sum()
+    }
+
+    fn stream(&self, topic: &str, partition: u32) -> Option<&NodeHandle> {
+        self.streams.get(topic)?.get(partition as usize)
+    }
+
+    fn spawn(&mut self, host: &str, topic: &Topic) {
+        let streams = topic.partitions
+            .iter()
+            .map(|partition| NodeReplicator::spawn(host, &self.channel, &self.info, partition.clone()))
+            .collect();
+        self.streams.insert(topic.name.clone(), streams);
+    }
+
+    fn client(&self) -> ReplicatorClient<Channel> {
+        let client = ReplicatorClient::new(self.channel.clone());
+        match self.info.codec {
+            Some(codec) => client.send_compressed(codec.encoding()).accept_compressed(codec.encoding()),
+            None => client,
         }
     }
 }
 
 #[derive(Debug)]
 pub struct ReplicatorMultiClient {
-    nodes: Mutex<HashMap<String, Channel>>,
-    in_sync: Mutex<HashMap<String, ac::Receiver<()>>>,
-    stash: Mutex<HashSet<Replica>>,
+    // locked after the topics when both are needed
+    nodes: Mutex<HashMap<String, Node>>,
+    topics: Mutex<HashMap<String, Arc<Topic>>>,
+    logs: TopicLogs,
     suspected_count: AtomicUsize,
 
 }
 
 impl ReplicatorMultiClient {
 
-    const REQUEST_BLOCK_TIME_ON_SYNC_MS: u64 = 30000;
-
     pub fn init() -> Self {
         let nodes = Mutex::new(HashMap::new());
-        let in_sync = Mutex::new(HashMap::new());
-        let stash = Mutex::new(HashSet::new());
+        let logs = TopicLogs::default();
+        let topics = [
+            Topic::new(DEFAULT_TOPIC, *TOPIC_PARTITIONS),
+            Topic::new(OFFSETS_TOPIC, 1),
+            Topic::new(LEASES_TOPIC, 1),
+        ]
+            .into_iter()
+            .map(|topic| {
+                logs.insert(&topic.name, &topic.logs());
+                (topic.name.clone(), Arc::new(topic))
+            })
+            .collect();
+        let topics = Mutex::new(topics);
         let suspected_count = AtomicUsize::new(0);
-        Self { nodes, stash, in_sync, suspected_count }
+        Self { nodes, topics, logs, suspected_count }
+    }
+
+    /// The partition logs of every topic by name
+    pub fn logs(&self) -> TopicLogs {
+        self.logs.clone()
+    }
+
+    pub async fn topic(&self, name: &str) -> Option<Arc<Topic>> {
+        self.topics.lock().await.get(name).cloned()
+    }
+
+    /// Reclaims the expired messages of every partition periodically, the log and the stash in the same pass
+    pub async fn sweep(self: Arc<Self>) {
+        sweeper(|now| {
+            let client = self.clone();
+            async move {
+                let topics = client.topics.lock().await.values().cloned().collect::<Vec<_>>();
+                let mut reclaimed = 0;
+                for partition in topics.iter().flat_map(|topic| &topic.partitions) {
+                    reclaimed += partition.reclaim(now).await;
+                }
+                reclaimed
+            }
+        }).await
+    }
+
+    /// Creates the topic with a replication actor per node and partition, `false` if it exists already
+    pub async fn create_topic(&self, name: &str, partitions: u32) -> bool {
+        let mut topics = self.topics.lock().await;
+        if topics.contains_key(name) {
+            return false
+        }
+        let topic = Arc::new(Topic::new(name, partitions));
+        topics.insert(name.to_string(), topic.clone());
+        self.logs.insert(name, &topic.logs());
+
+        let mut nodes = self.nodes.lock().await;
+        let mut clients = vec![];
+        for (host, node) in nodes.iter_mut() {
+            node.spawn(host, &topic);
+            clients.push((host.clone(), node.client()));
+        }
+        drop(nodes);
+        drop(topics);
+
+        log::info!("master: topic `{name}` created with {partitions} partition(s)");
+        let topic = TopicName { name: name.to_string(), partitions };
+        broadcast(clients, |mut client, topic| async move { client.create_topic(topic).await }, topic).await;
+        true
+    }
+
+    /// Deletes the topic with its replication actors and stash, `false` if there is no such topic
+    pub async fn delete_topic(&self, name: &str) -> bool {
+        let mut topics = self.topics.lock().await;
+        if topics.remove(name).is_none() {
+            return false
+        }
+        self.logs.remove(name);
+
+        let mut nodes = self.nodes.lock().await;
+        let mut clients = vec![];
+        for (host, node) in nodes.iter_mut() {
+            node.streams.remove(name);
+            clients.push((host.clone(), node.client()));
+        }
+        drop(nodes);
+        drop(topics);
+
+        log::info!("master: topic `{name}` deleted");
+        let topic = TopicName { name: name.to_string(), partitions: 0 };
+        broadcast(clients, |mut client, topic| async move { client.delete_topic(topic).await }, topic).await;
+        true
     }
 
     pub async fn verify_quorum(&self) -> bool {
@@ -83,129 +325,155 @@ impl ReplicatorMultiClient {
         meets
     }
 
-    pub async fn replicate(&self, message: Message, request: RequestBody) {
-        let wc = max(
-            min(
-                self.nodes.lock().await.len() + 1,
-                request.wc as usize
-            ),
-            1,
-        );
-
-        let mut replica = Replica::from(&message);
-        if let Some(order) = request.__ordering { replica.order = order }
-        {
-            let mut stash = self.stash.lock().await;
-            stash.insert(replica.clone());
-        }
-        let wc_barrier = Arc::new(Barrier::new(wc));
-        for (host, ch) in self.nodes.lock().await.iter() {
-            let rep = replica.clone();
-            let host = host.clone();
-            let ch = ch.clone();
-            let barrier = wc_barrier.clone();
-            let sync_end_event = match self.in_sync.lock().await.get(&host) {
-                Some(event) => Some(event.clone()),
-                None => None
+    /// Reserves room for `n` messages in the in-flight window of the partition on every node that has it,
+    /// a node with a full window does not take part in the write and gets the messages from the stash later.
+    /// The write is rejected before it gets an ordering if the admitted nodes cannot satisfy the write concern
+    /// or the whole replication queue is full
+    pub async fn admit(&self, partition: &Partition, n: usize, wc: &Requirement) -> Result<Admission, Backpressure> {
+        let nodes = self.nodes.lock().await;
+        let mut admission = Admission { n, nodes: HashMap::new() };
+        let mut admitted: Vec<Labels> = vec![NODE_LABELS.clone()];
+        let mut full = None;
+        for (host, node) in nodes.iter() {
+            let stream = match node.stream(&partition.topic, partition.index) {
+                Some(stream) => stream,
+                None => continue,
             };
-            tokio::spawn(async move {
-                if let Some(event) = sync_end_event {
-                    Self::block_if_in_sync(&host, event).await
+            match stream.reserve(n, *NODE_INFLIGHT_WINDOW) {
+                Ok(backlog) => {
+                    admission.nodes.insert(host.clone(), backlog);
+                    admitted.push(node.info.labels.clone());
                 }
-                Self::replicate_per_node(rep, host, ch).await;
-                barrier.wait().await;
-            });
-        }
-
-        match wc {
-            1 => log::info!("master: non-blocking replication... WRITE CONCERN - {wc}"),
-            _ => log::info!("master: blocking replication... WRITE CONCERN - {wc}"),
-        }
-        wc_barrier.wait().await;
-        log::info!("master: replication call completed")
-    }
-
-    async fn replicate_per_node(message: Replica, host: String, conn: Channel) {
-
-        let content = message.content.clone();
-        let mut att = Attempts::default();
-        log::info!("{host}: {message:?} sent for replication");
-        'done: {
-            while att.next() {
-                let mut client = ReplicatorClient::new(conn.clone());
-                let request = Request::new(message.clone());
-                let response = client.replicate(request).await;
-                let success = match response {
-                    Ok(body) => {
-                        let ack = body.into_inner();
-                        log::info!("{host}: message {content:?} - replication status - {ack:?}");
-                        true
-                    }
-                    Err(e) => {
-                        log::error!("{host}: message {content:?} - replication failed - {e:?}");
-                        false
-                    }
-                };
+                Err(backlog) => {
+                    log::warn!("{host}: in-flight window of `{partition}` is full ({backlog}), the write skips the node");
+                    full.get_or_insert(Backpressure::WindowFull { host: host.clone(), backlog });
+                }
+            }
+        }
+        if let Some(full) = full.filter(|_| !wc.satisfied(&admitted)) {
+            log::warn!("master: the admitted nodes cannot satisfy the write concern {wc}, rejecting the write");
+            return Err(full)
+        }
+        let queued = nodes.values().map(Node::backlog).sum::<usize>();
+        if queued > *REPLICATION_QUEUE_LIMIT {
+            log::warn!("master: replication queue limit is reached ({queued}), rejecting the write");
+            return Err(Backpressure::QueueFull { queued })
+        }
 
-                if success { break 'done }
+        Ok(admission)
+    }
 
-                log::error!(
-                    "{host}: request failed, retrying after {} ms, {} attempts left...",
-                    att.backoff_ms.as_millis(),
-                    att.n);
+    /// Resolves the write concern against the current membership, the master labels included
+    pub async fn resolve(&self, wc: &WriteConcern) -> Result<Requirement, String> {
+        let nodes = self.nodes.lock().await;
+        let members = std::iter::once(&*NODE_LABELS)
+            .chain(nodes.values().map(|node| &node.info.labels))
+            .collect::<Vec<_>>();
 
-                att.delay().await
+        wc.resolve(&members)
+    }
+
+    /// Returns the nodes that have applied the contiguous `replicas` once the write concern is satisfied,
+    /// only the successful acknowledgements are counted. A zero `wtimeout` waits for them indefinitely
+    pub async fn replicate(
+        &self,
+        partition: &Partition,
+        replicas: Vec<Replica>,
+        wc: Requirement,
+        wtimeout: Duration,
+        mut admission: Admission,
+    ) -> Result<Vec<String>, WriteConcernError> {
+        let order = replicas.first().map(|replica| replica.order).unwrap_or_default();
+        let nodes = self.nodes.lock().await;
+        let mut acks = nodes
+            .iter()
+            .filter_map(|(host, node)| Some((host, node, node.stream(&partition.topic, partition.index)?)))
+            .filter(|(host, _, _)| admission.take(host))
+            .map(|(host, node, stream)| {
+                let (host, labels, ack) = (host.clone(), node.info.labels.clone(), stream.submit(replicas.clone()));
+                async move { (host, labels, ack.await.unwrap_or(false)) }
+            })
+            .collect::<FuturesUnordered<_>>();
+        drop(nodes);
 
+        // the master itself counts as the first acknowledgement
+        let mut acked = vec![];
+        let mut acked_labels: Vec<Labels> = vec![NODE_LABELS.clone()];
+        match wc.satisfied(&acked_labels) {
+            true => log::info!("master: non-blocking replication... WRITE CONCERN - {wc}"),
+            false => log::info!("master: blocking replication... WRITE CONCERN - {wc}"),
+        }
+        let collect = async {
+            while !wc.satisfied(&acked_labels) {
+                match acks.next().await {
+                    Some((host, labels, true)) => {
+                        acked.push(host);
+                        acked_labels.push(labels);
+                    }
+                    Some((host, _, false)) => log::warn!("{host}: message ({order}) is not acknowledged"),
+                    None => break,
+                }
             }
-            log::error!("{host}: message completely failed to replicate \
-            (it will be stashed and re-processed again after the target node recovery)");
-        }
-    }
-
-    pub async fn sync_node(&self, host: &str, channel: &Channel, from_order: u32) {
-        let host = host.to_string();
-        let futures: FuturesUnordered<JoinHandle<()>>;
-        let (tx, rx) = async_channel::bounded(10);
-        {
-            let mut in_sync = self.in_sync.lock().await;
-            in_sync.insert(host.clone(), rx);
-        }
-        {
-            futures = self.stash.lock().await
-                .iter()
-                .filter(|msg| msg.order > from_order)
-                .map(|msg|
-                    tokio::spawn(
-                        Self::replicate_per_node(
-                            msg.clone(),
-                            host.clone(),
-                            channel.clone(),
-                        )
-                    )
-                )
-                .collect::<FuturesUnordered<_>>();
-        }
-        futures.for_each(|_| async {}).await;
-
-        let _ = tx.send(()).await;
-        let mut in_sync = self.in_sync.lock().await;
-        in_sync.remove(&host);
-    }
-
-    async fn block_if_in_sync(host: &str, sync_end_event: ac::Receiver<()>) {
-        log::info!("{host}: node in sync mode, blocked to process the replication");
-        match timeout(
-            Duration::from_millis(Self::REQUEST_BLOCK_TIME_ON_SYNC_MS),
-            sync_end_event.recv()
-        ).await {
-            Err(_) => log::info!("{host}: the sync waiting time has elapsed, back to work"),
-            _ => {}
         };
+        let timed_out = match wtimeout.is_zero() {
+            true => { collect.await; false }
+            false => timeout(wtimeout, collect).await.is_err(),
+        };
+
+        if timed_out {
+            // the node actors keep the message queued, so only the waiting is abandoned
+            log::error!("master: replication call timed out, WRITE CONCERN - {wc}, acknowledged by {acked:?}");
+            return Err(WriteConcernError::TimedOut { required: wc, acked, wtimeout })
+        }
+        if !wc.satisfied(&acked_labels) {
+            log::error!("master: replication call failed, WRITE CONCERN - {wc}, acknowledged by {acked:?}");
+            return Err(WriteConcernError::Failed { required: wc, acked })
+        }
+        log::info!("master: replication call completed");
+        Ok(acked)
     }
 
-    pub async fn add_node(&self, host: &str, channel: &Channel) {
+    /// Starts the replication of every topic to the node, `announced` are the topics sent in its join Ack,
+    /// the ones created or deleted since then are announced to the node the same way as to the others
+    pub async fn add_node(&self, host: &str, channel: &Channel, info: NodeInfo, announced: &HashMap<String, u32>) {
+        let topics = self.topics.lock().await;
         let mut nodes = self.nodes.lock().await;
-        nodes.insert(host.to_string(), channel.clone());
+        let mut node = Node { info, channel: channel.clone(), streams: HashMap::new() };
+        for topic in topics.values() {
+            node.spawn(host, topic);
+        }
+        let client = vec![(host.to_string(), node.client())];
+        nodes.insert(host.to_string(), node);
+
+        let created = topics
+            .values()
+            .filter(|topic| !announced.contains_key(&topic.name))
+            .map(|topic| TopicName { name: topic.name.clone(), partitions: topic.partitions.len() as u32 })
+            .collect::<Vec<_>>();
+        let deleted = announced
+            .keys()
+            .filter(|name| !topics.contains_key(*name))
+            .map(|name| TopicName { name: name.clone(), partitions: 0 })
+            .collect::<Vec<_>>();
+        drop(nodes);
+        drop(topics);
+
+        for topic in created {
+            broadcast(client.clone(), |mut client, topic| async move { client.create_topic(topic).await }, topic).await;
+        }
+        for topic in deleted {
+            broadcast(client.clone(), |mut client, topic| async move { client.delete_topic(topic).await }, topic).await;
+        }
+    }
+
+    /// The partition count of every topic, sent to a joining node
+    pub async fn topic_partitions(&self) -> HashMap<String, u32> {
+        self.topics
+            .lock()
+            .await
+            .iter()
+            .map(|(name, topic)| (name.clone(), topic.partitions.len() as u32))
+            .collect()
     }
 
     pub async fn del_node(&self, name: &str) {
@@ -213,6 +481,32 @@ impl ReplicatorMultiClient {
         nodes.remove(name);
     }
 
+    pub async fn backlog(&self, topic: &str, partition: u32) -> HashMap<String, usize> {
+        let nodes = self.nodes.lock().await;
+        nodes.iter()
+            .filter_map(|(host, node)| Some((host.clone(), node.stream(topic, partition)?.backlog())))
+            .collect()
+    }
+
+    /// The storage metrics of the partition stash, `None` if there is no such partition
+    pub async fn stash_metrics(&self, topic: &str, partition: u32) -> Option<StorageMetrics> {
+        let partition = self.topic(topic).await?.partitions.get(partition as usize)?.clone();
+
+        Some(partition.stash_metrics().await)
+    }
+
+    pub async fn progress(&self, topic: &str, partition: u32) -> HashMap<String, u32> {
+        let nodes = self.nodes.lock().await;
+        nodes.iter()
+            .filter_map(|(host, node)| Some((host.clone(), node.stream(topic, partition)?.progress())))
+            .collect()
+    }
+
+    pub async fn compression(&self) -> HashMap<String, Option<Codec>> {
+        let nodes = self.nodes.lock().await;
+        nodes.iter().map(|(host, node)| (host.clone(), node.info.codec)).collect()
+    }
+
     pub fn increment_suspected(&self) {
         self.suspected_count.fetch_add(1, Ordering::Release);
     }
@@ -0,0 +1,265 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{env, fmt, io};
+
+use lazy_static::lazy_static;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use replog::common::compression::Codec;
+use replog::common::message::{compress_payload, default_content_type, expired, timestamp_ms, MessageLog, StorageMetrics};
+use replog::common::topics::MAX_PARTITIONS;
+use replog::common::utils::fnv1a;
+use crate::anti_entropy::Reports;
+use crate::node_replicator::Stash;
+use crate::replicator_client::{OrderingConflict, EPOCH};
+use crate::replicator_client::replicator::Replica;
+use crate::writer::{Append, Rejection};
+
+lazy_static! {
+    /// The partition count of the default topic and of a topic created without one
+    pub static ref TOPIC_PARTITIONS: u32 = env::var("TOPIC_PARTITIONS")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(1)
+        .clamp(1, MAX_PARTITIONS);
+}
+
+
+/// A partition of a topic with its own ordering sequence and stash, replicated to every node by a separate actor
+#[derive(Debug)]
+pub struct Partition {
+    pub topic: String,
+    pub index: u32,
+    next: AtomicU32,
+    pub log: MessageLog,
+    pub stash: Stash,
+    pub reports: Reports,
+    // the expired replicas whose payloads have been dropped from the stash
+    reclaimed: Mutex<BTreeSet<u32>>,
+}
+
+impl fmt::Display for Partition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.topic, self.index)
+    }
+}
+
+impl Partition {
+
+    fn new(topic: &str, index: u32) -> Self {
+        Self {
+            topic: topic.to_string(),
+            index,
+            next: AtomicU32::new(1),
+            log: MessageLog::new(),
+            stash: Arc::new(Mutex::new(BTreeMap::new())),
+            reports: Arc::new(Mutex::new(HashMap::new())),
+            reclaimed: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Reserves `n` consecutive orderings and returns the first one.
+    /// With `expected_last` the reservation succeeds only if it is the current head ordering,
+    /// the check and the reservation are a single atomic operation
+    fn reserve(&self, n: u32, expected_last: Option<u32>) -> Result<u32, Rejection> {
+        let reserved = self.next.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |next| match expected_last {
+            Some(last) if last.checked_add(1) != Some(next) => None,
+            _ => next.checked_add(n),
+        });
+        reserved.map_err(|next| match expected_last {
+            Some(last) if last.checked_add(1) != Some(next) => {
+                log::warn!("master: conditional write to `{self}` rejected, the head ordering is ({})", next - 1);
+                Rejection::Conflict(OrderingConflict { head: next - 1 })
+            }
+            _ => Rejection::OrderingExhausted(n),
+        })
+    }
+
+    /// Reserves a contiguous ordering range for the messages and stashes them
+    pub async fn stash(&self, append: &Append) -> Result<Vec<Replica>, Rejection> {
+        let n = append.messages.len() as u32;
+        let first = self.reserve(n, append.expected_last_order)?;
+        let batch_last = if n > 1 { first + n - 1 } else { 0 };
+        let timestamp = timestamp_ms();
+        let replicas = append.messages
+            .iter()
+            .zip(first..)
+            .map(|(draft, order)| Replica {
+                id: Uuid::new_v4().to_string(),
+                order: append.ordering.unwrap_or(order),
+                content: draft.content.clone(),
+                epoch: EPOCH.clone(),
+                headers: draft.headers.clone(),
+                content_type: draft.content_type
+                    .clone()
+                    .unwrap_or_else(|| default_content_type(&draft.content).to_string()),
+                timestamp,
+                producer_id: append.producer_id.clone().unwrap_or_default(),
+                topic: self.topic.clone(),
+                partition: self.index,
+                key: append.key.clone().unwrap_or_default(),
+                redacts: append.redacts.as_ref().map(|(id, _)| id.clone()).unwrap_or_default(),
+                redacted: false,
+                ttl_ms: draft.ttl_ms.unwrap_or_default(),
+                batch_last,
+            })
+            .collect::<Vec<_>>();
+        let codec = self.log.compression();
+        let mut stash = self.stash.lock().await;
+        // the stash is the source of the anti-entropy repairs, so the content must not survive there either
+        if let Some((id, order)) = &append.redacts {
+            if let Some(stashed) = stash.get_mut(order).filter(|stashed| &stashed.replica.id == id) {
+                stashed.redact();
+            }
+        }
+        stash.extend(replicas.iter().map(|replica| (replica.order, Stashed::store(replica.clone(), codec))));
+
+        Ok(replicas)
+    }
+
+    /// Drops the payloads of the messages expired by `now` from the log and the stash in one pass,
+    /// so the anti-entropy repairs do not bring them back, and returns their count
+    pub async fn reclaim(&self, now: u64) -> usize {
+        let count = self.log.sweep(now).await;
+        let mut stash = self.stash.lock().await;
+        let mut reclaimed = self.reclaimed.lock().await;
+        for (order, stashed) in stash.iter_mut().filter(|(_, stashed)| stashed.replica.expired(now)) {
+            if reclaimed.insert(*order) {
+                stashed.reclaim();
+            }
+        }
+
+        count
+    }
+
+    /// The same storage metrics as of the log, for the replicas kept in the stash
+    pub async fn stash_metrics(&self) -> StorageMetrics {
+        let stash = self.stash.lock().await;
+
+        StorageMetrics::new(
+            self.log.compression(),
+            stash.values().map(|stashed| (stashed.raw_len, stashed.compressed.as_ref().map(|(_, payload)| payload.len()))),
+        )
+    }
+}
+
+/// A stashed replica, its content is compressed with the payload codec the same way as in the log
+#[derive(Debug)]
+pub struct Stashed {
+    // when compressed, the replica content is moved into the payload
+    pub replica: Replica,
+    raw_len: usize,
+    compressed: Option<(Codec, Vec<u8>)>,
+}
+
+impl Stashed {
+    fn store(mut replica: Replica, codec: Option<Codec>) -> Self {
+        let raw_len = replica.content.len();
+        let compressed = compress_payload(&replica.content, codec, replica.order);
+        if compressed.is_some() {
+            replica.content = vec![];
+        }
+
+        Self { replica, raw_len, compressed }
+    }
+
+    /// A payload that cannot be decompressed is an error, it is never replicated as an empty content
+    pub fn load(&self) -> io::Result<Replica> {
+        let mut replica = self.replica.clone();
+        if let Some((codec, payload)) = &self.compressed {
+            replica.content = codec.decompress(payload).map_err(|e| {
+                log::error!("master: failed to decompress the stashed replica ({}) - {e:?}", replica.order);
+                e
+            })?;
+        }
+
+        Ok(replica)
+    }
+
+    fn redact(&mut self) {
+        self.replica.content = vec![];
+        self.replica.redacted = true;
+        self.raw_len = 0;
+        self.compressed = None;
+    }
+
+    fn reclaim(&mut self) {
+        self.replica.content = vec![];
+        self.replica.headers.clear();
+        self.raw_len = 0;
+        self.compressed = None;
+    }
+}
+
+impl Replica {
+    pub fn expired(&self, now: u64) -> bool {
+        expired(self.timestamp, (self.ttl_ms > 0).then_some(self.ttl_ms), now)
+    }
+
+    /// Whether both replicas have been appended with the same batch
+    pub fn batched_with(&self, other: &Replica) -> bool {
+        self.batch_last != 0 && self.batch_last == other.batch_last
+    }
+}
+
+/// The ordering range of the whole batch the stashed replica at `order` has been appended with,
+/// only the replica itself if it has been appended alone
+pub fn batch_range(stash: &BTreeMap<u32, Stashed>, order: u32) -> (u32, u32) {
+    match stash.get(&order).map(|stashed| &stashed.replica) {
+        Some(replica) if replica.batch_last >= order => {
+            let first = stash
+                .range(..order)
+                .rev()
+                .take_while(|(_, previous)| previous.replica.batched_with(replica))
+                .last()
+                .map_or(order, |(first, _)| *first);
+            (first, replica.batch_last)
+        }
+        _ => (order, order),
+    }
+}
+
+/// A named log split into partitions, the messages are ordered within a partition only
+#[derive(Debug)]
+pub struct Topic {
+    pub name: String,
+    pub partitions: Vec<Arc<Partition>>,
+    // the next partition of a write without a key
+    cursor: AtomicUsize,
+}
+
+impl Topic {
+
+    pub fn new(name: &str, partitions: u32) -> Self {
+        Self {
+            name: name.to_string(),
+            partitions: (0..partitions).map(|index| Arc::new(Partition::new(name, index))).collect(),
+            cursor: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn logs(&self) -> Vec<MessageLog> {
+        self.partitions.iter().map(|partition| MessageLog::from(&partition.log)).collect()
+    }
+
+    /// The partition given explicitly, the one of the key hash or the next one in turn
+    pub fn route(&self, append: &Append) -> Result<Arc<Partition>, Rejection> {
+        let n = self.partitions.len();
+        let index = match (append.partition, &append.key) {
+            (Some(partition), _) => partition as usize,
+            (None, Some(key)) => {
+                // the routing is part of the data layout, a key must keep its partition across releases and restarts
+                (fnv1a(key.as_bytes()) % n as u64) as usize
+            }
+            (None, None) if n == 1 => 0,
+            (None, None) if append.expected_last_order.is_some() => {
+                return Err(Rejection::AmbiguousPartition(n as u32))
+            }
+            (None, None) => self.cursor.fetch_add(1, Ordering::Relaxed) % n,
+        };
+
+        self.partitions.get(index).cloned().ok_or(Rejection::UnknownPartition(index as u32))
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use serde::Deserialize;
+
+pub type Labels = HashMap<String, String>;
+
+
+/// A write concern as requested by a client: a number of acknowledgements including the master,
+/// a named one (`"majority"`, `"all"`) or a tag set like `{"zone": "each"}` / `{"zone": 2}`
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum WriteConcern {
+    Nodes(u8),
+    Named(String),
+    Tags(HashMap<String, TagQuorum>),
+}
+
+/// A number of distinct tag values to be acknowledged, `"each"` stands for every value present in the cluster
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum TagQuorum {
+    Values(usize),
+    Each(String),
+}
+
+/// A write concern resolved against the current cluster membership
+#[derive(Debug, Clone)]
+pub enum Requirement {
+    Acks(usize),
+    Tags(BTreeMap<String, usize>),
+}
+
+impl WriteConcern {
+
+    /// Parses a write concern given as a string, e.g. in a query: `2`, `majority` or `{"zone":"each"}`
+    pub fn parse(wc: &str) -> Self {
+        serde_json::from_str(wc).unwrap_or_else(|_| Self::Named(wc.to_string()))
+    }
+
+    /// `members` are the labels of every node including the master
+    pub fn resolve(&self, members: &[&Labels]) -> Result<Requirement, String> {
+        let total = members.len();
+        match self {
+            Self::Nodes(n) => Ok(Requirement::Acks((*n as usize).clamp(1, total))),
+            Self::Named(name) => match name.as_str() {
+                "majority" => Ok(Requirement::Acks(total / 2 + 1)),
+                "all" => Ok(Requirement::Acks(total)),
+                _ => Err(format!("Unknown write concern `{name}`, expected a number, `majority`, `all` or a tag set")),
+            },
+            Self::Tags(tags) => {
+                let mut required = BTreeMap::new();
+                for (tag, quorum) in tags {
+                    let present = distinct_values(tag, members.iter().copied());
+                    let n = match quorum {
+                        TagQuorum::Values(n) => *n,
+                        TagQuorum::Each(each) if each == "each" => present,
+                        TagQuorum::Each(other) => return Err(
+                            format!("Unknown quorum `{other}` of the tag `{tag}`, expected a number or `each`")
+                        ),
+                    };
+                    if present == 0 || n > present {
+                        return Err(format!(
+                            "The write concern cannot be satisfied: {n} distinct `{tag}` value(s) required, {present} present in the cluster"
+                        ))
+                    }
+                    required.insert(tag.clone(), n);
+                }
+                Ok(Requirement::Tags(required))
+            }
+        }
+    }
+}
+
+impl Requirement {
+
+    /// `acked` are the labels of the nodes that have applied the message including the master
+    pub fn satisfied<'a, I>(&self, acked: I) -> bool
+    where
+        I: IntoIterator<Item = &'a Labels> + Clone,
+    {
+        match self {
+            Self::Acks(n) => acked.into_iter().count() >= *n,
+            Self::Tags(tags) => tags
+                .iter()
+                .all(|(tag, n)| distinct_values(tag, acked.clone()) >= *n),
+        }
+    }
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Acks(n) => write!(f, "{n}"),
+            Self::Tags(tags) => write!(f, "{tags:?}"),
+        }
+    }
+}
+
+fn distinct_values<'a, I>(tag: &str, labels: I) -> usize
+where
+    I: IntoIterator<Item = &'a Labels>,
+{
+    labels
+        .into_iter()
+        .filter_map(|labels| labels.get(tag))
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(zone: &str) -> Labels {
+        HashMap::from([(String::from("zone"), zone.to_string())])
+    }
+
+    fn acks(requirement: Requirement) -> usize {
+        match requirement {
+            Requirement::Acks(n) => n,
+            Requirement::Tags(tags) => panic!("unexpected tag requirement {tags:?}"),
+        }
+    }
+
+    #[test]
+    fn resolves_counts_within_the_cluster_size() {
+        let (a, b, c) = (labels("a"), labels("b"), labels("c"));
+        let members = [&a, &b, &c];
+
+        assert_eq!(acks(WriteConcern::Nodes(2).resolve(&members).unwrap()), 2);
+        assert_eq!(acks(WriteConcern::Nodes(5).resolve(&members).unwrap()), 3);
+        assert_eq!(acks(WriteConcern::Nodes(0).resolve(&members).unwrap()), 1);
+    }
+
+    #[test]
+    fn resolves_majority_and_all() {
+        let (a, b, c, d) = (labels("a"), labels("b"), labels("c"), labels("d"));
+
+        assert_eq!(acks(WriteConcern::parse("majority").resolve(&[&a, &b, &c]).unwrap()), 2);
+        assert_eq!(acks(WriteConcern::parse("majority").resolve(&[&a, &b, &c, &d]).unwrap()), 3);
+        assert_eq!(acks(WriteConcern::parse("all").resolve(&[&a, &b, &c]).unwrap()), 3);
+        assert!(WriteConcern::parse("most").resolve(&[&a]).is_err());
+    }
+
+    #[test]
+    fn resolves_tag_quorums_against_the_present_values() {
+        let (a1, a2, b) = (labels("a"), labels("a"), labels("b"));
+        let members = [&a1, &a2, &b];
+
+        let each = WriteConcern::parse(r#"{"zone": "each"}"#).resolve(&members).unwrap();
+        assert!(matches!(each, Requirement::Tags(ref tags) if tags["zone"] == 2));
+        assert!(WriteConcern::parse(r#"{"zone": 3}"#).resolve(&members).is_err());
+        assert!(WriteConcern::parse(r#"{"rack": 1}"#).resolve(&members).is_err());
+        assert!(WriteConcern::parse(r#"{"zone": "some"}"#).resolve(&members).is_err());
+    }
+
+    #[test]
+    fn satisfied_by_acks_or_distinct_tag_values() {
+        let (a1, a2, b) = (labels("a"), labels("a"), labels("b"));
+
+        assert!(Requirement::Acks(2).satisfied([&a1, &a2]));
+        assert!(!Requirement::Acks(3).satisfied([&a1, &a2]));
+
+        let zones = Requirement::Tags(BTreeMap::from([(String::from("zone"), 2)]));
+        assert!(!zones.satisfied([&a1, &a2]));
+        assert!(zones.satisfied([&a1, &b]));
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, fmt};
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use replog::common::message::{payload, timestamp_ms, Message, MessageLog};
+use replog::common::groups::{ConsumerGroups, OffsetCommit, OFFSETS_TOPIC};
+use replog::common::queues::{Lease, LeaseEvent, WorkQueues, LEASES_TOPIC};
+use replog::common::topics::is_internal;
+use crate::SharedReplicator;
+use crate::replicator_client::{Admission, Backpressure, OrderingConflict, WriteConcernError, WTIMEOUT_MS};
+use crate::replicator_client::replicator::Replica;
+use crate::topics::Partition;
+use crate::write_concern::{Requirement, WriteConcern};
+
+/// The longest visibility timeout of a lease, 12 hours
+pub const MAX_LEASE_TIMEOUT_MS: u64 = 12 * 60 * 60 * 1000;
+
+lazy_static! {
+    /// How long a leased message stays invisible to the other consumers of the group if not given by the consumer
+    pub static ref LEASE_TIMEOUT_MS: u64 = env::var("LEASE_TIMEOUT_MS")
+        .unwrap_or_default()
+        .parse()
+        .unwrap_or(30000)
+        .clamp(1, MAX_LEASE_TIMEOUT_MS);
+}
+
+/// A message to be appended, the master assigns its ID, ordering and timestamp
+#[derive(Debug, Clone, Deserialize)]
+pub struct Draft {
+    #[serde(flatten, with = "payload")]
+    pub content: Vec<u8>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub content_type: Option<String>,  // `text/plain` or `application/octet-stream` by default
+    pub ttl_ms: Option<u64>,  // never expires by default
+}
+
+impl From<Vec<u8>> for Draft {
+    fn from(content: Vec<u8>) -> Self {
+        Self { content, headers: HashMap::new(), content_type: None, ttl_ms: None }
+    }
+}
+
+/// A write of one or more messages, whichever API it has come from
+#[derive(Debug)]
+pub struct Append {
+    pub topic: String,
+    pub partition: Option<u32>,  // routed by the key if not given
+    pub key: Option<String>,
+    pub messages: Vec<Draft>,
+    pub producer_id: Option<String>,
+    pub wc: WriteConcern,
+    pub wtimeout_ms: Option<u64>,
+    pub expected_last_order: Option<u32>,
+    // testing override of a single message ordering
+    pub ordering: Option<u32>,
+    // the ID and the ordering of the message a single tombstone redacts
+    pub redacts: Option<(String, u32)>,
+}
+
+
+/// Why a write has been refused before it got an ordering
+#[derive(Debug)]
+pub enum Rejection {
+    Empty,
+    UnknownTopic(String),
+    InternalTopic(String),
+    UnknownPartition(u32),
+    AmbiguousPartition(u32),
+    InvalidLeaseTimeout(u64),
+    UnknownMessage(String),
+    Redacted(String),
+    NoQuorum,
+    Backpressure(Backpressure),
+    InvalidConcern(String),
+    Conflict(OrderingConflict),
+    OrderingExhausted(u32),
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "The batch is empty"),
+            Self::UnknownTopic(name) => write!(f, "No topic `{name}`"),
+            Self::InternalTopic(name) => write!(f, "The topic `{name}` is internal"),
+            Self::UnknownPartition(partition) => write!(f, "No partition ({partition})"),
+            Self::AmbiguousPartition(n) => write!(
+                f, "A conditional write to a topic with {n} partitions requires a key or a partition"
+            ),
+            Self::InvalidLeaseTimeout(ms) => write!(
+                f, "Invalid lease timeout ({ms} ms), expected from 1 to {MAX_LEASE_TIMEOUT_MS} ms"
+            ),
+            Self::UnknownMessage(id) => write!(f, "No message with ID `{id}`"),
+            Self::Redacted(id) => write!(f, "The message `{id}` has no content to redact"),
+            Self::NoQuorum => write!(
+                f, "The service cannot save the message due to a temporary failure/absence of the required nodes. \
+                Please try later."
+            ),
+            Self::Backpressure(pressure) => write!(f, "{pressure}"),
+            Self::InvalidConcern(e) => write!(f, "{e}"),
+            Self::Conflict(conflict) => write!(f, "{conflict}"),
+            Self::OrderingExhausted(n) => write!(f, "The partition has no orderings left for {n} more message(s)"),
+        }
+    }
+}
+
+/// The messages appended to the master log and stashed, waiting for the replication
+pub struct Prepared {
+    partition: Arc<Partition>,
+    replicas: Vec<Replica>,
+    wc: Requirement,
+    wtimeout: Duration,
+    admission: Admission,
+}
+
+impl Prepared {
+
+    /// The ID and the ordering of every message
+    pub fn items(&self) -> Vec<(String, u32)> {
+        self.replicas.iter().map(|replica| (replica.id.clone(), replica.order)).collect()
+    }
+
+    pub fn partition(&self) -> u32 {
+        self.partition.index
+    }
+}
+
+pub struct Outcome {
+    pub partition: u32,
+    pub items: Vec<(String, u32)>,
+    pub acked: Result<Vec<String>, WriteConcernError>,
+    pub elapsed_ms: u128,
+}
+
+/// The write pipeline shared by every client API of the master
+#[derive(Clone)]
+pub struct Writer {
+    replicator: SharedReplicator,
+    groups: ConsumerGroups,
+    queues: WorkQueues,
+    // serializes the lease changes, so they are appended to the leases topic in the order they are decided
+    leasing: Arc<Mutex<()>>,
+}
+
+impl Writer {
+
+    pub fn new(replicator: SharedReplicator, groups: ConsumerGroups, queues: WorkQueues) -> Self {
+        Self { replicator, groups, queues, leasing: Arc::new(Mutex::new(())) }
+    }
+
+    pub fn log(&self, topic: &str, partition: u32) -> Option<MessageLog> {
+        self.replicator.logs().get(topic, partition)
+    }
+
+    /// Prepares a client write, the internal topics are written by the master only
+    pub async fn prepare(&self, append: &Append) -> Result<Prepared, Rejection> {
+        if is_internal(&append.topic) {
+            return Err(Rejection::InternalTopic(append.topic.clone()))
+        }
+
+        self.stage(append).await
+    }
+
+    /// Appends the offsets of the group to the offsets topic in a single batch, so they are committed atomically
+    /// and replicated like messages, and applies them on the master right away
+    pub async fn commit(
+        &self,
+        offsets: Vec<OffsetCommit>,
+        wc: WriteConcern,
+        wtimeout_ms: Option<u64>,
+    ) -> Result<Outcome, Rejection> {
+        let append = Append {
+            topic: OFFSETS_TOPIC.to_string(),
+            partition: None,
+            key: offsets.first().map(|commit| commit.group.clone()),
+            messages: offsets
+                .iter()
+                .map(|commit| Draft {
+                    content: serde_json::to_vec(commit).unwrap_or_default(),
+                    headers: HashMap::new(),
+                    content_type: Some(String::from("application/json")),
+                    ttl_ms: None,
+                })
+                .collect(),
+            producer_id: None,
+            wc,
+            wtimeout_ms,
+            expected_last_order: None,
+            ordering: None,
+            redacts: None,
+        };
+        let prepared = self.stage(&append).await?;
+        for (commit, (_, at)) in offsets.into_iter().zip(prepared.items()) {
+            self.groups.apply(commit, at);
+        }
+
+        Ok(self.replicate(prepared).await)
+    }
+
+    /// Appends a tombstone of the message to its partition, every node drops the content of the message
+    /// when the tombstone is applied and keeps its ordering slot. Without a partition the message is looked up
+    /// in every partition of the topic
+    pub async fn redact(
+        &self,
+        topic: &str,
+        partition: Option<u32>,
+        id: &str,
+        wc: WriteConcern,
+        wtimeout_ms: Option<u64>,
+    ) -> Result<Prepared, Rejection> {
+        if is_internal(topic) {
+            return Err(Rejection::InternalTopic(topic.to_string()))
+        }
+        let logs = self.replicator
+            .logs()
+            .partitions(topic)
+            .ok_or_else(|| Rejection::UnknownTopic(topic.to_string()))?;
+        let candidates = match partition {
+            Some(partition) => vec![(partition, logs.get(partition as usize).ok_or(Rejection::UnknownPartition(partition))?)],
+            None => (0..).zip(&logs).collect(),
+        };
+        let mut found = None;
+        for (partition, log) in candidates {
+            if let Some(message) = log.get(id).await {
+                found = Some((partition, message));
+                break
+            }
+        }
+        let (partition, message) = found.ok_or_else(|| Rejection::UnknownMessage(id.to_string()))?;
+        if message.redacted || message.redacts.is_some() {
+            return Err(Rejection::Redacted(id.to_string()))
+        }
+
+        self.prepare(&Append {
+            topic: topic.to_string(),
+            partition: Some(partition),
+            key: message.key,
+            messages: vec![Draft {
+                content: vec![],
+                headers: HashMap::new(),
+                content_type: None,
+                ttl_ms: None,
+            }],
+            producer_id: None,
+            wc,
+            wtimeout_ms,
+            expected_last_order: None,
+            ordering: None,
+            redacts: Some((id.to_string(), message.order)),
+        }).await
+    }
+
+    /// Leases the first message of the topic, from the first partition on, that is neither acknowledged nor leased
+    /// by the group, `None` if there is no such message. The lease is appended to the leases topic, so a failover
+    /// keeps it, and applied on the master right away
+    pub async fn lease(
+        &self,
+        group: &str,
+        topic: &str,
+        timeout_ms: Option<u64>,
+        wc: WriteConcern,
+        wtimeout_ms: Option<u64>,
+    ) -> Result<Option<(Lease, Message, Outcome)>, Rejection> {
+        if is_internal(topic) {
+            return Err(Rejection::InternalTopic(topic.to_string()))
+        }
+        let timeout_ms = timeout_ms.unwrap_or(*LEASE_TIMEOUT_MS);
+        if !(1..=MAX_LEASE_TIMEOUT_MS).contains(&timeout_ms) {
+            return Err(Rejection::InvalidLeaseTimeout(timeout_ms))
+        }
+        let logs = self.replicator
+            .logs()
+            .partitions(topic)
+            .ok_or_else(|| Rejection::UnknownTopic(topic.to_string()))?;
+
+        let leasing = self.leasing.lock().await;
+        let now = timestamp_ms();
+        let mut found = None;
+        'partitions: for (partition, log) in (0..).zip(&logs) {
+            let head = log.head().await;
+            let mut after = 0;
+            while let Some((order, deliveries)) = self.queues.next_available(group, topic, partition, after, head) {
+                // a missing ordering may have been reserved by a write that has not reached the log yet
+                let Some(message) = log.slot(order).await else { continue 'partitions };
+                if !message.expired(now) && !message.redacted && message.redacts.is_none() {
+                    found = Some((partition, message, deliveries));
+                    break 'partitions
+                }
+                // expired or redacted, or a tombstone, it never becomes a work item again
+                self.queues.skip(group, topic, partition, order);
+                after = order;
+            }
+        }
+        let (partition, message, deliveries) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let lease = Lease {
+            lease_id: Uuid::new_v4().to_string(),
+            topic: topic.to_string(),
+            partition,
+            order: message.order,
+            expires_at_ms: timestamp_ms().saturating_add(timeout_ms),
+            deliveries: deliveries + 1,
+        };
+        let event = LeaseEvent::Leased { group: group.to_string(), lease: lease.clone() };
+        let prepared = self.stage(&Self::lease_append(group, &event, wc, wtimeout_ms)).await?;
+        self.queues.apply(&event);
+        drop(leasing);
+
+        Ok(Some((lease, message, self.replicate(prepared).await)))
+    }
+
+    /// Acknowledges the leased message, so it is never delivered to the group again, or releases it
+    /// for the redelivery right away, `None` if the group has no such lease
+    pub async fn settle(
+        &self,
+        group: &str,
+        lease_id: &str,
+        ack: bool,
+        wc: WriteConcern,
+        wtimeout_ms: Option<u64>,
+    ) -> Result<Option<Outcome>, Rejection> {
+        let leasing = self.leasing.lock().await;
+        if self.queues.lease(group, lease_id).is_none() {
+            return Ok(None)
+        }
+        let event = match ack {
+            true => LeaseEvent::Acked { group: group.to_string(), lease_id: lease_id.to_string() },
+            false => LeaseEvent::Nacked { group: group.to_string(), lease_id: lease_id.to_string() },
+        };
+        let prepared = self.stage(&Self::lease_append(group, &event, wc, wtimeout_ms)).await?;
+        self.queues.apply(&event);
+        drop(leasing);
+
+        Ok(Some(self.replicate(prepared).await))
+    }
+
+    fn lease_append(group: &str, event: &LeaseEvent, wc: WriteConcern, wtimeout_ms: Option<u64>) -> Append {
+        Append {
+            topic: LEASES_TOPIC.to_string(),
+            partition: None,
+            key: Some(group.to_string()),
+            messages: vec![Draft {
+                content: serde_json::to_vec(event).unwrap_or_default(),
+                headers: HashMap::new(),
+                content_type: Some(String::from("application/json")),
+                ttl_ms: None,
+            }],
+            producer_id: None,
+            wc,
+            wtimeout_ms,
+            expected_last_order: None,
+            ordering: None,
+            redacts: None,
+        }
+    }
+
+    /// Checks the quorum, the backpressure and the write concern, so a rejected write never gets an ordering,
+    /// then reserves the orderings and appends the messages to the log of the partition
+    async fn stage(&self, append: &Append) -> Result<Prepared, Rejection> {
+        if append.messages.is_empty() {
+            return Err(Rejection::Empty)
+        }
+        let topic = self.replicator
+            .topic(&append.topic)
+            .await
+            .ok_or_else(|| Rejection::UnknownTopic(append.topic.clone()))?;
+        let partition = topic.route(append)?;
+        if !self.replicator.verify_quorum().await {
+            return Err(Rejection::NoQuorum)
+        }
+        let wc = self.replicator.resolve(&append.wc).await.map_err(Rejection::InvalidConcern)?;
+        let admission = self.replicator
+            .admit(&partition, append.messages.len(), &wc)
+            .await
+            .map_err(Rejection::Backpressure)?;
+
+        let replicas = partition.stash(append).await?;
+        let mut messages = replicas.iter().map(Message::from).collect::<Vec<_>>();
+        match messages.len() {
+            1 => {
+                let message = messages.remove(0);
+                log::info!("{:?} received", message);
+                partition.log.add(message).await;
+            }
+            n => {
+                log::info!("Batch of {n} messages received");
+                partition.log.add_all(messages).await;
+            }
+        }
+
+        Ok(Prepared {
+            partition,
+            replicas,
+            wc,
+            wtimeout: Duration::from_millis(append.wtimeout_ms.unwrap_or(*WTIMEOUT_MS)),
+            admission,
+        })
+    }
+
+    pub async fn replicate(&self, prepared: Prepared) -> Outcome {
+        let items = prepared.items();
+        let started = Instant::now();
+        let acked = self.replicator
+            .replicate(&prepared.partition, prepared.replicas, prepared.wc, prepared.wtimeout, prepared.admission)
+            .await;
+
+        Outcome { partition: prepared.partition.index, items, acked, elapsed_ms: started.elapsed().as_millis() }
+    }
+}
@@ -5,9 +5,12 @@ use tonic::transport::Endpoint;
 use tonic::Request;
 
 use join_request::join_request_client::JoinRequestClient;
-use join_request::NodeState;
+use join_request::{NodeState, PartitionOrdering};
+use replog::common::compression::Codec;
 use replog::common::retry::Attempts;
-use replog::RPC_DEF_PORT;
+use replog::{NODE_LABELS, RPC_DEF_PORT};
+use replog::common::topics::DEFAULT_TOPIC;
+use crate::replicator_server::ReplicatedTopics;
 
 pub mod join_request {
     tonic::include_proto!("joinreq");
@@ -15,13 +18,24 @@ pub mod join_request {
 
 const MASTER_HOSTNAME: &str = "master";
 
-pub async fn try_join(ordering: u32) -> bool {
+pub async fn try_join(topics: &ReplicatedTopics) -> bool {
 
     let master_url =
         Endpoint::from_shared(format!("http://{}:{}", MASTER_HOSTNAME, *RPC_DEF_PORT)).unwrap();
 
     let host = get_hostname().unwrap_or_default();
-    let info = NodeState { host, ordering };
+    let orderings = topics.orderings().await;
+    let ordering = orderings.get(&(DEFAULT_TOPIC.to_string(), 0)).copied().unwrap_or_default();
+    let info = NodeState {
+        host,
+        ordering,
+        compression: Codec::supported(),
+        labels: NODE_LABELS.clone(),
+        partitions: orderings
+            .into_iter()
+            .map(|((topic, partition), ordering)| PartitionOrdering { topic, partition, ordering })
+            .collect(),
+    };
 
     let mut att = Attempts::default();
     log::info!("Joining to the master with the current message ordering ({ordering})...");
@@ -33,6 +47,11 @@ pub async fn try_join(ordering: u32) -> bool {
                 Ok(body) => {
                     let response = body.into_inner();
                     log::info!("Joining to the master status - {response:?}");
+                    if response.success {
+                        topics.set_compression(Codec::parse(&response.payload_compression)).await;
+                        topics.state.register_epoch(response.epoch.clone()).await;
+                        topics.sync_topics(&response.topics).await;
+                    }
                     response.success
                 }
                 Err(e) => {
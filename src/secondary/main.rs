@@ -5,26 +5,139 @@ use std::net::SocketAddr;
 use std::process::exit;
 use std::sync::atomic::{AtomicBool, Ordering};
 
-use actix_web::{get, web, web::Data, App, HttpRequest, HttpResponse, HttpServer, post};
+use actix_web::{get, web, web::{Data, Path, Query}, App, HttpRequest, HttpResponse, HttpServer, Scope, post};
 use log;
 
 use crate::join_requester::try_join;
-use common::message::MessageLog;
+use common::pagination::ReadQuery;
+use common::events::{self, StreamQuery};
+use common::subscription;
+use common::groups::{ConsumerGroups, GroupPath, OffsetPath, OFFSETS_TOPIC};
+use common::queues::{WorkQueues, LEASES_TOPIC};
+use common::topics::{MessageAt, MessageId, TopicLog, TopicLogs};
+use common::utils::ErrorBody;
 use replog::{common, SERVER_DEF_PORT, SERVER_WORKER_NUM};
-use crate::replicator_server::ReplicatedMessageLog;
+use crate::replicator_server::ReplicatedTopics;
 
 
 static SABOTAGE_MODE: AtomicBool = AtomicBool::new(false);
 
 
 #[get("/messages")]
-async fn list_messages(log: Data<MessageLog>, req: HttpRequest) -> HttpResponse {
+async fn list_messages(log: TopicLog, query: Query<ReadQuery>, req: HttpRequest) -> HttpResponse {
     log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
 
-    let messages = log.get_all().await;
-    log::info!("Log has {} messages", messages.len());
+    match query.read(&log).await {
+        Ok(page) => {
+            log::info!("Read {} messages, the head ordering is ({})", page.messages.len(), page.head);
+            HttpResponse::Ok().json(page)
+        }
+        Err(e) => HttpResponse::BadRequest().json(ErrorBody::new(e)),
+    }
+}
+
+#[get("/messages/stream")]
+async fn stream_messages(log: TopicLog, query: Query<StreamQuery>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    // the consumers of a node must not see the messages out of order
+    events::stream(&log, &query, &req, true).await
+}
+
+#[get("/messages/ws")]
+async fn subscribe_messages(topic: TopicLog, body: web::Payload, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    subscription::subscribe(&topic, &req, body, true)
+}
+
+#[get("/messages/at/{order}")]
+async fn get_message_at(log: TopicLog, at: Path<MessageAt>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    match log.get_at(at.order).await {
+        Some(message) => HttpResponse::Ok().json(message),
+        None => HttpResponse::NotFound().json(ErrorBody::new(format!("No message with ordering ({})", at.order))),
+    }
+}
+
+/// The payload as is under its original content type
+#[get("/messages/{id}/content")]
+async fn get_message_content(log: TopicLog, path: Path<MessageId>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    match log.get(&path.id).await {
+        Some(message) => HttpResponse::Ok().content_type(message.content_type).body(message.content),
+        None => HttpResponse::NotFound().json(ErrorBody::new(format!("No message with ID `{}`", path.id))),
+    }
+}
+
+#[get("/messages/{id}")]
+async fn get_message(log: TopicLog, path: Path<MessageId>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    match log.get(&path.id).await {
+        Some(message) => HttpResponse::Ok().json(message),
+        None => HttpResponse::NotFound().json(ErrorBody::new(format!("No message with ID `{}`", path.id))),
+    }
+}
+
+#[get("/metrics")]
+async fn metrics(log: TopicLog, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
 
-    HttpResponse::Ok().json(messages)
+    HttpResponse::Ok().json(log.metrics().await)
+}
+
+#[get("/groups")]
+async fn list_groups(groups: Data<ConsumerGroups>, logs: Data<TopicLogs>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    let mut reports = vec![];
+    for group in groups.names() {
+        reports.extend(groups.lag(&group, &logs).await);
+    }
+
+    HttpResponse::Ok().json(reports)
+}
+
+#[get("/groups/{group}/offsets")]
+async fn get_group_offsets(
+    groups: Data<ConsumerGroups>,
+    logs: Data<TopicLogs>,
+    path: Path<GroupPath>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    match groups.lag(&path.group, &logs).await {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => HttpResponse::NotFound().json(ErrorBody::new(format!("No group `{}`", path.group))),
+    }
+}
+
+#[get("/groups/{group}/offsets/{topic}/{partition}")]
+async fn get_offset(
+    groups: Data<ConsumerGroups>,
+    logs: Data<TopicLogs>,
+    path: Path<OffsetPath>,
+    req: HttpRequest,
+) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    match groups.offset(&path.group, &path.topic, path.partition, &logs).await {
+        Some(offset) => HttpResponse::Ok().json(offset),
+        None => HttpResponse::NotFound().json(ErrorBody::new(format!(
+            "No offset of group `{}` at `{}/{}`", path.group, path.topic, path.partition
+        ))),
+    }
+}
+
+#[get("/groups/{group}/leases")]
+async fn list_leases(queues: Data<WorkQueues>, path: Path<GroupPath>, req: HttpRequest) -> HttpResponse {
+    log::debug!("Called {} \"{}\" resource", req.method(), req.uri());
+
+    HttpResponse::Ok().json(queues.leases(&path.group))
 }
 
 #[post("/sabotage")]
@@ -35,11 +148,30 @@ async fn __sabotage() -> HttpResponse {
     HttpResponse::Ok().json("The sabotage mode switched")
 }
 
+/// The message resources of a topic partition, served for the default topic without the `/topics/{topic}` prefix
+/// and for the first partition without the `/partitions/{partition}` one
+fn messages(scope: Scope) -> Scope {
+    scope
+        .service(list_messages)
+        .service(stream_messages)
+        .service(subscribe_messages)
+        .service(get_message_at)
+        .service(get_message_content)
+        .service(get_message)
+        .service(metrics)
+}
+
 pub fn config(config: &mut web::ServiceConfig) {
+    // registered from the most specific one, so a shorter scope does not take over the longer paths
+    config.service(messages(web::scope("/api/v1/topics/{topic}/partitions/{partition}")));
+    config.service(messages(web::scope("/api/v1/topics/{topic}")));
     config.service(
-        web::scope("/api/v1")
-            .service(list_messages)
-            .service(__sabotage)
+        messages(web::scope("/api/v1"))
+            .service(list_groups)
+            .service(get_group_offsets)
+            .service(get_offset)
+            .service(list_leases)
+            .service(__sabotage),
     );
 }
 
@@ -47,24 +179,41 @@ pub fn config(config: &mut web::ServiceConfig) {
 async fn main() {
     common::utils::init_logger();
 
-    let repl_log = ReplicatedMessageLog::new();
-    log::debug!("Initialized ReplicatedMessageLog object");
+    let topics = ReplicatedTopics::new();
+    log::debug!("Initialized ReplicatedTopics object");
 
-    tokio::spawn(replicator_server::start((&repl_log).into()));
+    tokio::spawn(replicator_server::start(topics.clone()));
 
-    repl_log.state.sync_mode.toggle(true);
-    if !try_join(0).await {
+    topics.state.sync_mode.toggle(true);
+    if !try_join(&topics).await {
         log::error!("Failed to establish a connection with the master node, abort");
         exit(1)
     }
-    repl_log.state.sync_mode.toggle(false);
+    topics.state.sync_mode.toggle(false);
+
+    // the committed offsets are materialized from the replicated offsets topic, so they survive the master failure
+    let groups = ConsumerGroups::default();
+    if let Some(log) = topics.logs.get(OFFSETS_TOPIC, 0) {
+        tokio::spawn(groups.clone().follow(log));
+    }
+
+    // as are the leases, so a new master does not redeliver the messages leased or acknowledged already
+    let queues = WorkQueues::default();
+    if let Some(log) = topics.logs.get(LEASES_TOPIC, 0) {
+        tokio::spawn(queues.clone().follow(log));
+    }
+
+    tokio::spawn(topics.logs.clone().sweep());
 
-    let msg_log = MessageLog::from(&repl_log.log);
-    let app_log = Data::new(msg_log);
+    let app_logs = Data::new(topics.logs.clone());
+    let app_groups = Data::new(groups);
+    let app_queues = Data::new(queues);
     log::info!("Starting HTTP server");
     HttpServer::new(move || {
         App::new()
-            .app_data(app_log.clone())
+            .app_data(app_logs.clone())
+            .app_data(app_groups.clone())
+            .app_data(app_queues.clone())
             .configure(config)
             .default_service(web::route().to(|| HttpResponse::MethodNotAllowed()))
     })
@@ -2,7 +2,7 @@ use std::env;
 use std::time::Duration;
 use std::cmp::{max, min};
 use std::net::SocketAddr;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, AtomicI8, AtomicU32, Ordering};
 
@@ -12,7 +12,7 @@ use tokio::time::{interval, sleep, timeout};
 use tonic_health::pb::health_server::HealthServer;
 use tonic::{transport::Server, Request, Response, Status, async_trait};
 
-use replicator::{Ack, Replica};
+use replicator::{Ack, AckStatus, DigestRange, RangeDigest, RangeRepair, Replica, ReplicaBatch, TopicName};
 use replicator::replicator_server::{Replicator, ReplicatorServer};
 
 use sync_request::{EmptyAck, SyncClaim};
@@ -20,7 +20,11 @@ use sync_request::sync_request_server::{SyncRequest, SyncRequestServer};
 
 use replog::common::message::{Message, MessageLog};
 use replog::{RPC_DEF_PORT, REQ_TIMEOUT_MS, RPC_SERVER_RECONNECT_DELAY_MS};
+use replog::common::compression::Codec;
 use replog::common::heartbeats::HealthService;
+use replog::common::groups::OFFSETS_TOPIC;
+use replog::common::queues::LEASES_TOPIC;
+use replog::common::topics::{is_internal, TopicLogs, DEFAULT_TOPIC, MAX_PARTITIONS};
 use crate::join_requester::try_join;
 use crate::SABOTAGE_MODE;
 
@@ -31,8 +35,31 @@ pub mod sync_request {
     tonic::include_proto!("syncreq");
 }
 
+impl From<Replica> for Message {
+    fn from(replica: Replica) -> Self {
+        Self {
+            id: replica.id,
+            order: replica.order,
+            content: replica.content,
+            headers: replica.headers,
+            content_type: replica.content_type,
+            timestamp: replica.timestamp,
+            producer_id: (!replica.producer_id.is_empty()).then_some(replica.producer_id),
+            key: (!replica.key.is_empty()).then_some(replica.key),
+            redacts: (!replica.redacts.is_empty()).then_some(replica.redacts),
+            redacted: replica.redacted,
+            ttl_ms: (replica.ttl_ms > 0).then_some(replica.ttl_ms),
+        }
+    }
+}
+
 type ReplReq = Request<Replica>;
 type ReplRes = Result<Response<Ack>, Status>;
+type BatchReq = Request<ReplicaBatch>;
+type DigestReq = Request<DigestRange>;
+type DigestRes = Result<Response<RangeDigest>, Status>;
+type RepairReq = Request<RangeRepair>;
+type TopicReq = Request<TopicName>;
 type SyncReq = Request<SyncClaim>;
 type SyncRes = Result<Response<EmptyAck>, Status>;
 
@@ -54,12 +81,14 @@ lazy_static! {
 }
 
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub enum MessageStatus {
-    Invalid,
+    Duplicate,
+    Stale,
     Belated,
     Correct,
     Disordered,
+    Buffered,
 }
 
 #[derive(Debug, Default)]
@@ -92,7 +121,10 @@ pub struct ReplicationState {
     pub current_ordering: Arc<AtomicU32>,
     pub messages_lost: Arc<AtomicI8>,
     pub unique_identifiers: Arc<RwLock<HashSet<String>>>,
-    pub sync_mode: Arc<SyncMode>
+    // registered, but held back by the order correction or the replication delay, so not applied yet
+    pub pending_identifiers: Arc<RwLock<HashSet<String>>>,
+    pub sync_mode: Arc<SyncMode>,
+    pub epoch: Arc<RwLock<String>>,
 }
 
 impl ReplicationState {
@@ -101,7 +133,33 @@ impl ReplicationState {
             current_ordering: Arc::new(AtomicU32::new(0)),
             messages_lost: Arc::new(AtomicI8::new(0)),
             unique_identifiers: Arc::new(RwLock::new(HashSet::new())),
-            sync_mode: Arc::new(SyncMode::default())
+            pending_identifiers: Arc::new(RwLock::new(HashSet::new())),
+            sync_mode: Arc::new(SyncMode::default()),
+            epoch: Arc::new(RwLock::new(String::new())),
+        }
+    }
+
+    /// A fresh state of another topic, the sync mode and the master epoch are node-wide
+    pub fn fork(&self) -> Self {
+        Self {
+            sync_mode: self.sync_mode.clone(),
+            epoch: self.epoch.clone(),
+            ..Self::new()
+        }
+    }
+
+    /// A message of another master epoch (e.g. before the master restart) cannot be trusted
+    /// until the node rejoins and adopts the current one
+    pub async fn foreign_epoch(&self, epoch: &str) -> bool {
+        let current = self.epoch.read().await;
+        !current.is_empty() && !epoch.is_empty() && *current != epoch
+    }
+
+    pub async fn register_epoch(&self, epoch: String) {
+        let mut current = self.epoch.write().await;
+        if *current != epoch {
+            log::info!("Master epoch registered - {epoch}");
+            *current = epoch;
         }
     }
 
@@ -127,6 +185,27 @@ impl ReplicationState {
         set.insert(identifier);
     }
 
+    pub async fn pending(&self, identifier: &String) -> bool {
+        let set = self.pending_identifiers.read().await;
+        set.contains(identifier)
+    }
+
+    /// Registers the ID of a message that is applied later, it stays pending until `release_ids`
+    pub async fn register_pending_id(&self, identifier: String) {
+        self.pending_identifiers.write().await.insert(identifier.clone());
+        self.register_id(identifier).await;
+    }
+
+    pub async fn release_ids<'a, I>(&self, identifiers: I)
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let mut set = self.pending_identifiers.write().await;
+        for identifier in identifiers {
+            set.remove(identifier);
+        }
+    }
+
     pub fn register_ordering(&self, ordering: u32) {
         self.current_ordering.store(ordering, Ordering::Release);
     }
@@ -160,18 +239,14 @@ impl ReplicatedMessageLog {
         }
     }
 
-    pub async fn validate(&self, msg: &Replica) -> MessageStatus {
-        if self.validate_uniqueness(&msg.id).await == MessageStatus::Correct {
-            self.validate_ordering(msg.order).await
-        } else {
-            MessageStatus::Invalid
-        }
-    }
-
     async fn validate_uniqueness(&self, msg_id: &String) -> MessageStatus {
         if self.state.duplicates(msg_id).await {
+            if self.state.pending(msg_id).await {
+                log::info!("The message {msg_id} is received already and waits to be applied");
+                return MessageStatus::Buffered
+            }
             log::warn!("Message duplication detected: {msg_id}");
-            MessageStatus::Invalid
+            MessageStatus::Duplicate
         } else {
             MessageStatus::Correct
         }
@@ -188,7 +263,7 @@ impl ReplicatedMessageLog {
                 log::warn!(
                     "The message ordering ({msg_ordering}) is repeating according to the global one ({curr_order}). Aborting..."
                 );
-                MessageStatus::Invalid
+                MessageStatus::Stale
             }
         } else if !(self.state.consecutive_ordering(msg_ordering)) {
             log::info!(
@@ -228,55 +303,307 @@ impl ReplicatedMessageLog {
             _ => {}
         }
     }
+
+    /// Appends contiguous replicas at once
+    async fn apply(&self, replicas: Vec<Replica>, status: MessageStatus) {
+        sleep(*REPL_DELAY_MS).await;
+
+        let last = match replicas.last() {
+            Some(replica) => replica.order,
+            None => return,
+        };
+        let ids = replicas.iter().map(|replica| replica.id.clone()).collect::<Vec<_>>();
+        let messages = replicas.into_iter().map(Message::from).collect::<Vec<_>>();
+        match messages.len() {
+            1 => log::info!("{:?} replicated", messages[0]),
+            n => log::info!("Batch of {n} messages up to ordering ({last}) replicated"),
+        }
+        self.log.add_all(messages).await;
+        self.state.release_ids(&ids).await;
+
+        if !(status == MessageStatus::Belated) {
+            self.state.register_ordering(last);
+        }
+    }
+
+    /// Validates and applies contiguous replicas as a single message, skipping the ones applied already
+    async fn receive(&self, replicas: Vec<Replica>) -> ReplRes {
+        // the epoch and the topics of the master are not taken yet, so the master has to retry rather than rejoin the node
+        if self.state.sync_mode.enabled() {
+            return Err(Status::unavailable("The node is joining the master"))
+        }
+        // the replicas of a batch share the master epoch
+        if let Some(first) = replicas.first() {
+            if self.state.foreign_epoch(&first.epoch).await {
+                log::warn!("The message ({}) belongs to another master epoch ({})", first.order, first.epoch);
+                return Ok(self.ack(AckStatus::RejectedEpoch))
+            }
+        }
+
+        let mut fresh = Vec::with_capacity(replicas.len());
+        let mut buffered = false;
+        for replica in replicas {
+            match self.validate_uniqueness(&replica.id).await {
+                MessageStatus::Correct => fresh.push(replica),
+                MessageStatus::Buffered => buffered = true,
+                _ => {}
+            }
+        }
+        if buffered {
+            // a resend of the messages held back by the order correction, they must not count as applied yet
+            return Ok(self.ack(AckStatus::Buffered))
+        }
+        let status = match fresh.first() {
+            Some(first) => self.validate_ordering(first.order).await,
+            None => MessageStatus::Duplicate,
+        };
+        match status {
+            MessageStatus::Duplicate => return Ok(self.ack(AckStatus::Duplicate)),
+            MessageStatus::Stale => return Ok(self.ack(AckStatus::RejectedStale)),
+            _ => {}
+        }
+
+        for replica in &fresh {
+            self.state.register_pending_id(replica.id.clone()).await;
+        }
+        if status == MessageStatus::Disordered {
+            // held back until the preceding messages arrive, so the master is free to send them
+            let repl_log = ReplicatedMessageLog::from(self);
+            tokio::spawn(async move {
+                repl_log.correct_ordering(fresh[0].order).await;
+                repl_log.apply(fresh, status).await;
+            });
+            return Ok(self.ack(AckStatus::Buffered));
+        }
+
+        self.apply(fresh, status).await;
+
+        match SABOTAGE_MODE.load(Ordering::Acquire) {
+            false => Ok(self.ack(AckStatus::Applied)),
+            true => Err(Status::internal("Internal server error"))
+        }
+    }
+
+    fn ack(&self, status: AckStatus) -> Response<Ack> {
+        Response::new(Ack {
+            success: matches!(status, AckStatus::Applied | AckStatus::Duplicate | AckStatus::Buffered),
+            status: status as i32,
+            ordering: self.state.get_ordering(),
+        })
+    }
+}
+
+/// The replicated partition logs of every topic
+#[derive(Clone)]
+pub struct ReplicatedTopics {
+    topics: Arc<RwLock<HashMap<String, Vec<ReplicatedMessageLog>>>>,
+    pub logs: TopicLogs,
+    // the node-wide part of the replication state, shared by every partition
+    pub state: ReplicationState,
+}
+
+impl ReplicatedTopics {
+
+    pub fn new() -> Self {
+        let default = ReplicatedMessageLog::new();
+        let state = default.state.clone();
+        let offsets = ReplicatedMessageLog { state: state.fork(), log: MessageLog::new() };
+        let leases = ReplicatedMessageLog { state: state.fork(), log: MessageLog::new() };
+        let logs = TopicLogs::default();
+        logs.insert(DEFAULT_TOPIC, &[MessageLog::from(&default.log)]);
+        logs.insert(OFFSETS_TOPIC, &[MessageLog::from(&offsets.log)]);
+        logs.insert(LEASES_TOPIC, &[MessageLog::from(&leases.log)]);
+        let topics = Arc::new(RwLock::new(HashMap::from([
+            (DEFAULT_TOPIC.to_string(), vec![default]),
+            (OFFSETS_TOPIC.to_string(), vec![offsets]),
+            (LEASES_TOPIC.to_string(), vec![leases]),
+        ])));
+
+        Self { topics, logs, state }
+    }
+
+    /// Returns the log of the topic partition, an empty name stands for the default topic.
+    /// The topics are created only by the master, so a replication call never brings a deleted one back
+    pub async fn partition(&self, name: &str, partition: u32) -> Result<ReplicatedMessageLog, Status> {
+        let name = if name.is_empty() { DEFAULT_TOPIC } else { name };
+        self.topics
+            .read()
+            .await
+            .get(name)
+            .and_then(|logs| logs.get(partition as usize))
+            .map(ReplicatedMessageLog::from)
+            .ok_or_else(|| Status::not_found(format!("No partition ({partition}) of topic `{name}`")))
+    }
+
+    /// Creates the topic from scratch, the state of a topic deleted under the same name is dropped,
+    /// so the new one starts from the first ordering as it does on the master
+    pub async fn create(&self, name: &str, partitions: u32) {
+        if name == DEFAULT_TOPIC || is_internal(name) {
+            return
+        }
+        let mut topics = self.topics.write().await;
+        if topics.remove(name).is_some() {
+            log::warn!("Topic `{name}` exists already, its state is reset");
+        }
+        self.grow(&mut topics, name, partitions.clamp(1, MAX_PARTITIONS));
+    }
+
+    fn grow<'a>(
+        &self,
+        topics: &'a mut HashMap<String, Vec<ReplicatedMessageLog>>,
+        name: &str,
+        partitions: u32,
+    ) -> &'a [ReplicatedMessageLog] {
+        let logs = topics.entry(name.to_string()).or_default();
+        if logs.len() < partitions as usize {
+            let codec = self.log_compression();
+            for _ in logs.len()..partitions as usize {
+                let log = ReplicatedMessageLog { state: self.state.fork(), log: MessageLog::new() };
+                log.log.set_compression(codec);
+                logs.push(log);
+            }
+            log::info!("Topic `{name}` has {partitions} partition(s)");
+            self.logs.insert(name, &logs.iter().map(|log| MessageLog::from(&log.log)).collect::<Vec<_>>());
+        }
+
+        logs
+    }
+
+    pub async fn delete(&self, name: &str) {
+        if name == DEFAULT_TOPIC || is_internal(name) {
+            return
+        }
+        if self.topics.write().await.remove(name).is_some() {
+            self.logs.remove(name);
+            log::info!("Topic `{name}` deleted");
+        }
+    }
+
+    /// Keeps only the topics of the master, so the ones deleted while the node was away are dropped,
+    /// the missing topics and partitions are added while the existing ones keep their messages
+    pub async fn sync_topics(&self, partitions: &HashMap<String, u32>) {
+        {
+            let mut topics = self.topics.write().await;
+            for (name, n) in partitions {
+                self.grow(&mut topics, name, (*n).clamp(1, MAX_PARTITIONS));
+            }
+        }
+        let stale = self.topics
+            .read()
+            .await
+            .keys()
+            .filter(|name| !partitions.contains_key(*name))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in stale {
+            self.delete(&name).await;
+        }
+    }
+
+    /// The current ordering of every topic partition
+    pub async fn orderings(&self) -> HashMap<(String, u32), u32> {
+        self.topics
+            .read()
+            .await
+            .iter()
+            .flat_map(|(name, logs)| {
+                logs.iter()
+                    .zip(0..)
+                    .map(|(log, partition)| ((name.clone(), partition), log.state.get_ordering()))
+            })
+            .collect()
+    }
+
+    pub async fn set_compression(&self, codec: Option<Codec>) {
+        for log in self.topics.read().await.values().flatten() {
+            log.log.set_compression(codec);
+        }
+    }
+
+    fn log_compression(&self) -> Option<Codec> {
+        self.logs.get(DEFAULT_TOPIC, 0).and_then(|log| log.compression())
+    }
 }
 
 #[async_trait]
-impl Replicator for ReplicatedMessageLog {
+impl Replicator for ReplicatedTopics {
 
     async fn replicate(&self, request: ReplReq) -> ReplRes {
 
         let replica_msg: Replica = request.into_inner();
         log::info!("{:?} received", replica_msg);
 
-        let status = self.validate(&replica_msg).await;
-        if status == MessageStatus::Invalid {
-            return Ok(Response::new(Ack { success: false }));
-        }
+        self.partition(&replica_msg.topic, replica_msg.partition).await?.receive(vec![replica_msg]).await
+    }
 
-        self.state.register_id(replica_msg.id.clone()).await;
-        if status == MessageStatus::Disordered {
-            self.correct_ordering(replica_msg.order).await;
-        }
+    async fn replicate_batch(&self, request: BatchReq) -> ReplRes {
 
-        sleep(*REPL_DELAY_MS).await;
+        let batch = request.into_inner().replicas;
+        log::info!("Batch of {} messages received", batch.len());
 
-        let message = Message {
-            content: replica_msg.content,
-        };
-        self.log.add(message.clone()).await;
-        log::info!("{:?} replicated", message);
+        // the replicas of a batch share the topic partition
+        let (topic, partition) = batch
+            .first()
+            .map(|replica| (replica.topic.clone(), replica.partition))
+            .unwrap_or_default();
+        self.partition(&topic, partition).await?.receive(batch).await
+    }
 
-        if !(status == MessageStatus::Belated) {
-            self.state.register_ordering(replica_msg.order);
+    async fn digest(&self, request: DigestReq) -> DigestRes {
+        let range = request.into_inner();
+        let log = self.partition(&range.topic, range.partition).await?;
+        let (digest, count) = log.log.digest(range.from, range.to, range.now).await;
+
+        Ok(Response::new(RangeDigest { digest, count, ordering: log.state.get_ordering() }))
+    }
+
+    async fn repair(&self, request: RepairReq) -> ReplRes {
+        let repair = request.into_inner();
+        log::warn!(
+            "Repairing the divergent ordering range [{}, {}] of `{}/{}` with {} message(s)",
+            repair.from, repair.to, repair.topic, repair.partition, repair.replicas.len()
+        );
+
+        let log = self.partition(&repair.topic, repair.partition).await?;
+        let mut messages = Vec::with_capacity(repair.replicas.len());
+        for replica in repair.replicas {
+            log.state.register_id(replica.id.clone()).await;
+            messages.push(Message::from(replica));
         }
+        log.log.replace_range(repair.from, repair.to, messages).await;
 
-        match SABOTAGE_MODE.load(Ordering::Acquire) {
-            false => Ok(Response::new(Ack { success: true })),
-            true => Err(Status::internal("Internal server error"))
+        Ok(log.ack(AckStatus::Applied))
+    }
+
+    async fn create_topic(&self, request: TopicReq) -> ReplRes {
+        let topic = request.into_inner();
+        if !(1..=MAX_PARTITIONS).contains(&topic.partitions) {
+            return Err(Status::invalid_argument(format!(
+                "Invalid partition count ({}), expected from 1 to {MAX_PARTITIONS}", topic.partitions
+            )))
         }
+        self.create(&topic.name, topic.partitions).await;
+
+        Ok(self.partition(&topic.name, 0).await?.ack(AckStatus::Applied))
+    }
+
+    async fn delete_topic(&self, request: TopicReq) -> ReplRes {
+        let name = request.into_inner().name;
+        self.delete(&name).await;
+
+        Ok(Response::new(Ack { success: true, status: AckStatus::Applied as i32, ordering: 0 }))
     }
 }
 
 #[async_trait]
-impl SyncRequest for ReplicatedMessageLog {
+impl SyncRequest for ReplicatedTopics {
     async fn sync(&self, _: SyncReq) -> SyncRes {
-        let ordering = self.state.get_ordering();
-
         if !self.state.sync_mode.enabled() {
             let mode = self.state.sync_mode.clone();
+            let topics = self.clone();
             mode.toggle(true);
             tokio::spawn(async move {
-                try_join(ordering).await;
+                try_join(&topics).await;
                 mode.toggle(false)
             });
         }
@@ -288,18 +615,29 @@ impl SyncRequest for ReplicatedMessageLog {
 }
 
 
-pub async fn start(repl_log: ReplicatedMessageLog) {
+pub async fn start(topics: ReplicatedTopics) {
     let addr = SocketAddr::from(([0, 0, 0, 0], *RPC_DEF_PORT));
 
     let dur = Duration::from_millis(*RPC_SERVER_RECONNECT_DELAY_MS);
     loop {
         log::info!("Starting replication server");
         let health_service = HealthService {};
+        let mut replicator = ReplicatorServer::new(topics.clone());
+        let mut sync_request = SyncRequestServer::new(topics.clone());
+        // the master picks the codec, a response is compressed only if the request has offered the same one
+        for codec in [Codec::Gzip, Codec::Zstd] {
+            replicator = replicator
+                .accept_compressed(codec.encoding())
+                .send_compressed(codec.encoding());
+            sync_request = sync_request
+                .accept_compressed(codec.encoding())
+                .send_compressed(codec.encoding());
+        }
         match Server::builder()
             .timeout(Duration::from_millis(*REQ_TIMEOUT_MS))
             .add_service(HealthServer::new(health_service))
-            .add_service(ReplicatorServer::new(ReplicatedMessageLog::from(&repl_log)))
-            .add_service(SyncRequestServer::new(ReplicatedMessageLog::from(&repl_log)))
+            .add_service(replicator)
+            .add_service(sync_request)
             .serve(addr)
             .await {
             Ok(_) => break,